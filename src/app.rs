@@ -2,8 +2,13 @@ extern crate tempfile;
 
 use std::fs::{self, File};
 use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use rename;
+use rename::buffer::{self, BufferFormat};
+use rename::conflict::Conflict;
+use rename::escape;
+use rename::{self, ConflictPolicy, Error, Operation, RealFs, RenamePlan, Report};
 
 pub enum SortOrder {
     Alphabetical,
@@ -17,14 +22,14 @@ pub struct SortOption {
 
 enum RenameType {
     Directory {
-        dir: String,
+        dir: PathBuf,
         editor: String,
         filter_dirs: bool,
         depth: Option<usize>,
         sorting: Option<SortOption>,
     },
     LeftFile {
-        file: String,
+        file: PathBuf,
         editor: String,
         sorting: Option<SortOption>,
     },
@@ -33,77 +38,156 @@ enum RenameType {
         sorting: Option<SortOption>,
     },
     FileCompare {
-        left: String,
-        right: String,
+        left: PathBuf,
+        right: PathBuf,
     },
 }
 
 pub struct RenameOp {
-    is_demo: bool,
     rename_type: RenameType,
+    buffer: BufferFormat,
+    // What to do with the files under their edited names, unless a line says otherwise
+    operation: Operation,
+    // Files whose line is removed from a numbered buffer are deleted
+    delete_removed: bool,
+    // Print the plan and simulate it instead of running it
+    dry_run: bool,
+    options: rename::RenameOptions,
 }
 
 impl RenameOp {
     pub fn from_dir(
-        dir: &str,
+        dir: &Path,
         editor: &str,
         depth: Option<usize>,
         filter_dirs: bool,
-        is_demo: bool,
         sorting: Option<SortOption>,
     ) -> Self {
         RenameOp {
-            is_demo,
             rename_type: RenameType::Directory {
-                dir: dir.to_string(),
+                dir: dir.to_path_buf(),
                 editor: editor.to_string(),
                 depth,
                 filter_dirs,
                 sorting,
             },
+            buffer: BufferFormat::default(),
+            operation: Operation::default(),
+            delete_removed: false,
+            dry_run: false,
+            options: rename::RenameOptions::default(),
         }
     }
 
-    pub fn from_stdin(editor: &str, is_demo: bool, sorting: Option<SortOption>) -> Self {
+    pub fn from_stdin(editor: &str, sorting: Option<SortOption>) -> Self {
         RenameOp {
-            is_demo,
             rename_type: RenameType::StdinInput {
                 editor: editor.to_string(),
                 sorting,
             },
+            buffer: BufferFormat::default(),
+            operation: Operation::default(),
+            delete_removed: false,
+            dry_run: false,
+            options: rename::RenameOptions::default(),
         }
     }
 
-    pub fn from_left(file: &str, editor: &str, is_demo: bool, sorting: Option<SortOption>) -> Self {
+    pub fn from_left(file: &Path, editor: &str, sorting: Option<SortOption>) -> Self {
         RenameOp {
-            is_demo,
             rename_type: RenameType::LeftFile {
-                file: file.to_string(),
+                file: file.to_path_buf(),
                 editor: editor.to_string(),
                 sorting,
             },
+            buffer: BufferFormat::default(),
+            operation: Operation::default(),
+            delete_removed: false,
+            dry_run: false,
+            options: rename::RenameOptions::default(),
         }
     }
 
-    pub fn from_compare(left: &str, right: &str, is_demo: bool) -> Self {
+    pub fn from_compare(left: &Path, right: &Path) -> Self {
         RenameOp {
-            is_demo,
             rename_type: RenameType::FileCompare {
-                left: left.to_string(),
-                right: right.to_string(),
+                left: left.to_path_buf(),
+                right: right.to_path_buf(),
             },
+            buffer: BufferFormat::default(),
+            operation: Operation::default(),
+            delete_removed: false,
+            dry_run: false,
+            options: rename::RenameOptions::default(),
         }
     }
 
+    pub fn buffer(mut self, format: BufferFormat) -> Self {
+        self.buffer = format;
+        self
+    }
+
+    pub fn operation(mut self, op: Operation) -> Self {
+        self.operation = op;
+        self
+    }
+
+    pub fn relative_links(mut self, relative: bool) -> Self {
+        self.options.relative_links = relative;
+        self
+    }
+
+    pub fn delete_removed(mut self, delete: bool, recursive: bool) -> Self {
+        self.delete_removed = delete;
+        self.options.recursive_delete = recursive;
+        self
+    }
+
+    pub fn transactional(mut self, transactional: bool) -> Self {
+        self.options.transactional = transactional;
+        self
+    }
+
+    pub fn on_conflict(mut self, policy: ConflictPolicy) -> Self {
+        self.options.on_conflict = policy;
+        self.options.prompt = Some(Arc::new(ask));
+        self
+    }
+
+    pub fn prune_empty(mut self, prune_empty: bool) -> Self {
+        self.options.prune_empty = prune_empty;
+        self
+    }
+
+    pub fn rename_dirs(mut self, rename_dirs: bool) -> Self {
+        self.options.rename_dirs = rename_dirs;
+        self
+    }
+
+    pub fn trash(mut self, dir: Option<PathBuf>) -> Self {
+        self.options.trash = dir;
+        self
+    }
+
+    pub fn journal(mut self, dir: Option<PathBuf>) -> Self {
+        self.options.journal = dir;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
     fn directory_contents(
         &self,
-        dir: &str,
-        contents: &mut Vec<String>,
+        dir: &Path,
+        contents: &mut Vec<PathBuf>,
         depth: Option<usize>,
         filter_dirs: bool,
     ) {
         use std::fs::metadata;
-        use walkdir::WalkDir;;
+        use walkdir::WalkDir;
 
         let walker = if let Some(n) = depth {
             WalkDir::new(dir).follow_links(false).max_depth(n)
@@ -115,11 +199,18 @@ impl RenameOp {
             match entry {
                 Ok(p) => match metadata(p.path()) {
                     Ok(md) => {
-                        let file = p.path().to_str().unwrap();
-                        if md.is_file() {
-                            contents.push(file.to_owned());
-                        } else if !filter_dirs {
-                            contents.push(file.to_owned());
+                        let file = p.path();
+                        if p.file_name()
+                            .to_string_lossy()
+                            .starts_with(rename::TEMP_PREFIX)
+                        {
+                            println!(
+                                "Warning - leftover from an interrupted rename, see --recover: {}",
+                                file.display()
+                            );
+                        }
+                        if md.is_file() || !filter_dirs {
+                            contents.push(file.to_path_buf());
                         }
                     }
                     Err(e) => println!("Error reading metadata: {}", e),
@@ -129,24 +220,19 @@ impl RenameOp {
         }
     }
 
-    fn write_temp_file(&self, fnames: &Vec<String>) -> tempfile::NamedTempFile {
+    fn write_temp_file(&self, fnames: &[PathBuf]) -> tempfile::NamedTempFile {
         let mut nfile = tempfile::NamedTempFile::new().unwrap();
 
-        for name in fnames {
-            write!(nfile, "{}\n", name).unwrap();
-        }
+        buffer::write(&mut nfile, fnames, self.buffer, self.operation).unwrap();
 
         nfile.flush().unwrap();
         nfile
     }
 
-    fn open_file_with_editor(&self, file: &str, editor: &str) -> bool {
+    fn open_file_with_editor(&self, file: &Path, editor: &str) -> bool {
         use std::process::{Command, ExitStatus};
 
-        let editor_cmd = format!("{} {}", editor, file);
-        if self.is_demo {
-            println!("Editor command: {}", editor_cmd);
-        }
+        let editor_cmd = format!("{} {}", editor, file.display());
 
         let mut cmd = Command::new(editor);
         let mut exit_status: Option<ExitStatus> = Option::None;
@@ -164,50 +250,102 @@ impl RenameOp {
         }
     }
 
-    fn read_from_editor(&self, froms: &Vec<String>, editor: &str, tos: &mut Vec<String>) {
+    fn read_from_editor(
+        &self,
+        froms: &[PathBuf],
+        editor: &str,
+        tos: &mut Vec<(usize, Operation, PathBuf)>,
+    ) -> Result<(), Error> {
         if froms.is_empty() {
             println!("Nothing to rename!");
-            return;
+            return Ok(());
         }
 
-        let temp_file = self.write_temp_file(&froms);
-        let ok = self.open_file_with_editor(temp_file.path().to_str().unwrap(), editor);
+        let temp_file = self.write_temp_file(froms);
+        let ok = self.open_file_with_editor(temp_file.path(), editor);
 
         if !ok {
             println!("Something went wrong!");
-            return;
+            return Ok(());
+        }
+
+        let contents = fs::read(temp_file.path()).expect("Something went wrong while reading file");
+        tos.extend(buffer::read(
+            &contents,
+            froms,
+            self.buffer,
+            self.operation,
+            self.delete_removed,
+        )?);
+
+        if !self.dry_run && !self.confirm_deletions(froms, tos) {
+            return Err(Error::Cancelled);
+        }
+        Ok(())
+    }
+
+    // Lists the files marked for deletion and asks before going ahead
+    fn confirm_deletions(&self, froms: &[PathBuf], tos: &[(usize, Operation, PathBuf)]) -> bool {
+        let deleted: Vec<&PathBuf> = froms
+            .iter()
+            .zip(tos.iter())
+            .filter(|&(_, &(_, op, _))| op == Operation::Delete)
+            .map(|(from, _)| from)
+            .collect();
+        if deleted.is_empty() {
+            return true;
+        }
+
+        let trash = self.options.trash.is_some();
+        println!("Files to {}:", if trash { "move to the trash" } else { "delete" });
+        for path in &deleted {
+            println!("  {}", path.display());
+        }
+        let files = if deleted.len() == 1 { "file" } else { "files" };
+        if trash {
+            print!("Move {} {} to the trash? [y/N] ", deleted.len(), files);
+        } else {
+            print!("Delete {} {}? [y/N] ", deleted.len(), files);
+        }
+        if io::stdout().flush().is_err() {
+            return false;
         }
 
-        self.read_from_file(temp_file.path().to_str().unwrap(), tos);
+        let mut answer = String::new();
+        match io::stdin().lock().read_line(&mut answer) {
+            Ok(_) => answer.trim().eq_ignore_ascii_case("y"),
+            Err(_) => false,
+        }
     }
 
-    fn read_from_file(&self, file: &str, contents: &mut Vec<String>) {
+    // Lists given by the user hold raw names, only the editor buffer is quoted
+    fn read_from_file(&self, file: &Path, contents: &mut Vec<PathBuf>) {
         let mut f = File::open(file).expect("file not found");
 
-        let mut lines = String::new();
-        f.read_to_string(&mut lines)
+        let mut lines: Vec<u8> = vec![];
+        f.read_to_end(&mut lines)
             .expect("Something went wrong while reading file");
 
-        for s in lines.split("\n") {
-            if s != "" {
-                contents.push(s.to_owned());
+        for s in lines.split(|&b| b == b'\n') {
+            if !s.is_empty() {
+                contents.push(PathBuf::from(escape::from_bytes(s.to_vec())));
             }
         }
     }
 
-    fn read_from_stdin(&self, contents: &mut Vec<String>) {
+    fn read_from_stdin(&self, contents: &mut Vec<PathBuf>) {
         let stdin = io::stdin();
-        for line in stdin.lock().lines() {
+        for line in stdin.lock().split(b'\n') {
             let file = line.unwrap();
             if file.is_empty() {
                 break;
             } else {
-                contents.push(file);
+                contents.push(PathBuf::from(escape::from_bytes(file)));
             }
         }
     }
 
-    fn sort_alphabetical(&self, files: &mut Vec<String>, ascending: bool) {
+    fn sort_alphabetical(&self, files: &mut [PathBuf], ascending: bool) {
         files.sort_by(|x, y| {
             if ascending {
                 x.cmp(y)
@@ -217,18 +355,18 @@ impl RenameOp {
         });
     }
 
-    fn sort_dirs_first(&self, files: &mut Vec<String>, ascending: bool) {
-        let mut folders: Vec<String> = vec![];
-        let mut normal_files: Vec<String> = vec![];
+    fn sort_dirs_first(&self, files: &mut Vec<PathBuf>, ascending: bool) {
+        let mut folders: Vec<PathBuf> = vec![];
+        let mut normal_files: Vec<PathBuf> = vec![];
 
-        for ref s in files.iter() {
+        for s in files.iter() {
             match fs::metadata(s) {
                 Ok(m) => if m.is_dir() {
-                    folders.push(s.to_string());
+                    folders.push(s.clone());
                 } else {
-                    normal_files.push(s.to_string());
+                    normal_files.push(s.clone());
                 },
-                Err(_) => normal_files.push(s.to_string()),
+                Err(_) => normal_files.push(s.clone()),
             }
         }
 
@@ -240,7 +378,7 @@ impl RenameOp {
         files.append(&mut normal_files);
     }
 
-    fn sort_files(&self, files: &mut Vec<String>, sort_option: &Option<SortOption>) {
+    fn sort_files(&self, files: &mut Vec<PathBuf>, sort_option: &Option<SortOption>) {
         match sort_option {
             Some(SortOption {
                 order: SortOrder::Alphabetical,
@@ -254,9 +392,10 @@ impl RenameOp {
         }
     }
 
-    pub fn rename(&self) -> Result<i32, String> {
-        let mut froms: Vec<String> = vec![];
-        let mut tos: Vec<String> = vec![];
+    pub fn rename(&self) -> Result<Report, Error> {
+        let mut froms: Vec<PathBuf> = vec![];
+        // What to do with each file, with the line of the buffer or list that says so
+        let mut tos: Vec<(usize, Operation, PathBuf)> = vec![];
 
         match self.rename_type {
             RenameType::Directory {
@@ -266,25 +405,28 @@ impl RenameOp {
                 filter_dirs,
                 ref sorting,
             } => {
-                self.directory_contents(&dir, &mut froms, depth, filter_dirs);
+                self.directory_contents(dir, &mut froms, depth, filter_dirs);
                 self.sort_files(&mut froms, sorting);
-                self.read_from_editor(&froms, &editor, &mut tos);
+                self.read_from_editor(&froms, editor, &mut tos)?;
             }
             RenameType::LeftFile {
                 ref file,
                 ref editor,
                 ref sorting,
             } => {
-                self.read_from_file(&file, &mut froms);
+                self.read_from_file(file, &mut froms);
                 self.sort_files(&mut froms, sorting);
-                self.read_from_editor(&froms, &editor, &mut tos);
+                self.read_from_editor(&froms, editor, &mut tos)?;
             }
             RenameType::FileCompare {
                 ref left,
                 ref right,
             } => {
-                self.read_from_file(&left, &mut froms);
-                self.read_from_file(&right, &mut tos);
+                let mut names: Vec<PathBuf> = vec![];
+                self.read_from_file(left, &mut froms);
+                self.read_from_file(right, &mut names);
+                let lines = names.into_iter().enumerate();
+                tos.extend(lines.map(|(i, name)| (i + 1, self.operation, name)));
             }
             RenameType::StdinInput {
                 ref editor,
@@ -292,10 +434,66 @@ impl RenameOp {
             } => {
                 self.read_from_stdin(&mut froms);
                 self.sort_files(&mut froms, sorting);
-                self.read_from_editor(&froms, &editor, &mut tos);
+                self.read_from_editor(&froms, editor, &mut tos)?;
             }
         }
 
-        rename::bulk_rename(&froms, &tos, self.is_demo)
+        if froms.len() != tos.len() {
+            return Err(Error::LineCount {
+                expected: froms.len(),
+                found: tos.len(),
+            });
+        }
+
+        let mut plan = RenamePlan::on(Arc::new(real_fs()), self.options.clone());
+        for (from, (line, op, to)) in froms.into_iter().zip(tos) {
+            plan.add_line(line, from, op, to);
+        }
+        let plan = plan.validate()?;
+        if !self.dry_run {
+            return plan.execute();
+        }
+
+        println!("Dry run, nothing will be changed. The plan runs these steps:");
+        for step in plan.preview() {
+            println!("  {}", step);
+        }
+        plan.simulate()
+    }
+}
+
+// The real filesystem, showing how far copies of large trees have got
+pub fn real_fs() -> RealFs {
+    RealFs::new().on_progress(|tree, done, total| {
+        println!("Copying {}: {}/{}", tree.display(), done, total)
+    })
+}
+
+fn ask(conflict: &Conflict) -> ConflictPolicy {
+    println!("Conflict on\n  {}", conflict);
+    for claim in &conflict.claims {
+        println!("  line {}: {} {}", claim.0, claim.1, claim.2);
+    }
+
+    loop {
+        print!("[s]kip, [o]verwrite, [n]umber, [b]ackup or [a]bort? ");
+        if io::stdout().flush().is_err() {
+            return ConflictPolicy::Abort;
+        }
+
+        let mut answer = String::new();
+        match io::stdin().lock().read_line(&mut answer) {
+            Ok(0) | Err(_) => return ConflictPolicy::Abort,
+            Ok(_) => (),
+        }
+
+        match answer.trim() {
+            "s" => return ConflictPolicy::Skip,
+            "o" => return ConflictPolicy::Overwrite,
+            "n" => return ConflictPolicy::Suffix,
+            "b" => return ConflictPolicy::Backup,
+            "a" => return ConflictPolicy::Abort,
+            _ => (),
+        }
     }
 }
@@ -0,0 +1,251 @@
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use error::Error;
+use escape;
+use rename::Operation;
+
+/// How names are laid out in the editor buffer
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BufferFormat {
+    /// One name per line, paired with the files by position
+    #[default]
+    Plain,
+    /// Each line starts with the number of the file it names, as vidir does. Lines can be
+    /// reordered, and a file whose number is missing is left alone, or deleted if asked for.
+    Numbered,
+    /// Numbered lines that start with a command, as in a git rebase todo list, so that each
+    /// file can be renamed, copied, linked, deleted or kept
+    Commands,
+}
+
+// Commands are given by their first letter or in full
+const COMMANDS: &[(&str, &str, Operation)] = &[
+    ("r", "rename", Operation::Rename),
+    ("c", "copy", Operation::Copy),
+    ("l", "symlink", Operation::Symlink),
+    ("h", "hardlink", Operation::Hardlink),
+    ("d", "delete", Operation::Delete),
+    ("k", "keep", Operation::Keep),
+];
+
+/// Lines of a command buffer start out with the operation the run was asked for
+pub fn write<W: Write>(
+    out: &mut W,
+    names: &[PathBuf],
+    format: BufferFormat,
+    op: Operation,
+) -> io::Result<()> {
+    for (i, name) in names.iter().enumerate() {
+        let quoted = escape::quote(name.as_os_str());
+        match format {
+            BufferFormat::Plain => writeln!(out, "{}", quoted)?,
+            BufferFormat::Numbered => writeln!(out, "{}\t{}", i + 1, quoted)?,
+            BufferFormat::Commands => writeln!(out, "{} {}\t{}", letter(op), i + 1, quoted)?,
+        }
+    }
+    Ok(())
+}
+
+/// Reads the edited buffer back into what to do with every file in `froms`, in the same order,
+/// along with its new name and the (1-based) line of the buffer that says so. Lines without a
+/// command get `op`. A plain buffer gives the names in the order they are in, for the caller to
+/// pair up. Files removed from a numbered buffer are deleted when `delete_removed` is set, and
+/// keep the line they were written on.
+pub fn read(
+    contents: &[u8],
+    froms: &[PathBuf],
+    format: BufferFormat,
+    op: Operation,
+    delete_removed: bool,
+) -> Result<Vec<(usize, Operation, PathBuf)>, Error> {
+    // Blank lines are skipped, but still counted
+    let lines = contents
+        .split(|&b| b == b'\n')
+        .enumerate()
+        .filter(|&(_, s)| !s.is_empty())
+        .map(|(n, s)| (n + 1, s));
+
+    match format {
+        BufferFormat::Plain => Ok(lines
+            .map(|(n, s)| (n, op, PathBuf::from(escape::unquote(s))))
+            .collect()),
+        BufferFormat::Numbered => read_numbered(lines, froms, Some(op), delete_removed),
+        BufferFormat::Commands => read_numbered(lines, froms, None, delete_removed),
+    }
+}
+
+// Each line starts with a command, unless an operation is given for all of them
+fn read_numbered<'a, I>(
+    lines: I,
+    froms: &[PathBuf],
+    default_op: Option<Operation>,
+    delete_removed: bool,
+) -> Result<Vec<(usize, Operation, PathBuf)>, Error>
+where
+    I: Iterator<Item = (usize, &'a [u8])>,
+{
+    let mut tos: Vec<(usize, Operation, PathBuf)> = froms
+        .iter()
+        .enumerate()
+        .map(|(i, from)| {
+            if delete_removed {
+                (i + 1, Operation::Delete, PathBuf::new())
+            } else {
+                (i + 1, Operation::Keep, from.clone())
+            }
+        })
+        .collect();
+    let mut seen: Vec<Option<usize>> = vec![None; froms.len()];
+
+    for (n, mut line) in lines {
+        let op = match default_op {
+            Some(op) => op,
+            None => {
+                let len = line.iter().take_while(|b| !b.is_ascii_whitespace()).count();
+                let op = command(&line[..len]).ok_or_else(|| {
+                    Error::line(
+                        n,
+                        format!(
+                            "starts with unknown command {}",
+                            String::from_utf8_lossy(&line[..len])
+                        ),
+                    )
+                })?;
+                line = &line[len..];
+                line = &line[line.iter().take_while(|b| b.is_ascii_whitespace()).count()..];
+                op
+            }
+        };
+
+        let digits = line.iter().take_while(|b| b.is_ascii_digit()).count();
+        let id = ::std::str::from_utf8(&line[..digits])
+            .ok()
+            .and_then(|d| d.parse::<usize>().ok())
+            .ok_or_else(|| Error::line(n, "does not start with a number"))?;
+
+        if id == 0 || id > froms.len() {
+            return Err(Error::line(n, format!("names unknown file {}", id)));
+        }
+        if let Some(first) = seen[id - 1] {
+            return Err(Error::line(
+                n,
+                format!("names file {}, which line {} names already", id, first),
+            ));
+        }
+        seen[id - 1] = Some(n);
+
+        // The number is followed by a single tab, or a space if the editor replaced it
+        let mut name = &line[digits..];
+        if name.starts_with(b"\t") || name.starts_with(b" ") {
+            name = &name[1..];
+        }
+
+        // Files that are deleted or kept need no name
+        tos[id - 1] = match op {
+            Operation::Delete => (n, op, PathBuf::new()),
+            Operation::Keep => (n, op, froms[id - 1].clone()),
+            _ if name.is_empty() => {
+                return Err(Error::line(n, format!("gives no name to {}", op)));
+            }
+            _ => (n, op, PathBuf::from(escape::unquote(name))),
+        };
+    }
+
+    Ok(tos)
+}
+
+fn command(word: &[u8]) -> Option<Operation> {
+    COMMANDS
+        .iter()
+        .find(|&&(short, long, _)| word == short.as_bytes() || word == long.as_bytes())
+        .map(|&(_, _, op)| op)
+}
+
+fn letter(op: Operation) -> &'static str {
+    COMMANDS
+        .iter()
+        .find(|&&(_, _, command)| command == op)
+        .map_or("r", |&(short, _, _)| short)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, write, BufferFormat};
+    use error::Error;
+    use rename::Operation;
+    use std::path::PathBuf;
+
+    #[test]
+    fn numbered_buffer_test() {
+        let froms: Vec<PathBuf> = ["a.txt", " b.txt", "c.txt"].iter().map(PathBuf::from).collect();
+
+        let mut buffer: Vec<u8> = vec![];
+        write(&mut buffer, &froms, BufferFormat::Numbered, Operation::Rename).unwrap();
+        assert_eq!(buffer, b"1\ta.txt\n2\t\" b.txt\"\n3\tc.txt\n".to_vec());
+
+        // Lines are matched by number, and a missing line leaves its file alone
+        let edited = b"3 z.txt\n1\tx.txt\n";
+        let tos = read(edited, &froms, BufferFormat::Numbered, Operation::Rename, false).unwrap();
+        assert_eq!(
+            tos,
+            vec![
+                (2, Operation::Rename, PathBuf::from("x.txt")),
+                (2, Operation::Keep, froms[1].clone()),
+                (1, Operation::Rename, PathBuf::from("z.txt")),
+            ]
+        );
+
+        // Or deletes it, if asked for
+        let tos = read(edited, &froms, BufferFormat::Numbered, Operation::Rename, true).unwrap();
+        assert_eq!(tos[1].1, Operation::Delete);
+
+        for invalid in &[
+            &b"x.txt\n"[..],
+            b"4\tx.txt\n",
+            b"0\tx.txt\n",
+            b"1\tx.txt\n1\ty.txt\n",
+            b"1\t\n",
+        ] {
+            let res = read(invalid, &froms, BufferFormat::Numbered, Operation::Rename, false);
+            assert!(res.is_err());
+        }
+
+        // Errors give the line they are on, counting blank lines
+        let invalid = b"1\tx.txt\n\n1\ty.txt\n";
+        match read(invalid, &froms, BufferFormat::Numbered, Operation::Rename, false) {
+            Err(Error::Line { line, .. }) => assert_eq!(line, 3),
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn commands_buffer_test() {
+        let froms: Vec<PathBuf> = ["a.txt", "b.txt", "c.txt", "d.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+
+        let mut buffer: Vec<u8> = vec![];
+        write(&mut buffer, &froms[..1], BufferFormat::Commands, Operation::Copy).unwrap();
+        assert_eq!(buffer, b"c 1\ta.txt\n".to_vec());
+
+        // Commands are given short or in full, deleted and kept files need no name
+        let edited = b"c 1\tx.txt\nsymlink  2 y.txt\nd 3\nk 4\tignored.txt\n";
+        let tos = read(edited, &froms, BufferFormat::Commands, Operation::Rename, false).unwrap();
+        assert_eq!(
+            tos,
+            vec![
+                (1, Operation::Copy, PathBuf::from("x.txt")),
+                (2, Operation::Symlink, PathBuf::from("y.txt")),
+                (3, Operation::Delete, PathBuf::new()),
+                (4, Operation::Keep, froms[3].clone()),
+            ]
+        );
+
+        for invalid in &[&b"x 1\ta.txt\n"[..], b"1\ta.txt\n", b"r a.txt\n", b"h 1\n"] {
+            let res = read(invalid, &froms, BufferFormat::Commands, Operation::Rename, false);
+            assert!(res.is_err());
+        }
+    }
+}
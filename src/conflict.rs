@@ -0,0 +1,296 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use error::Error;
+use filesystem::Filesystem;
+use rename::{Operation, Rename};
+
+/// What to do when a target is claimed by more than one rename, or already exists and is not
+/// being renamed away. The file already at the target, if any, counts as the first claimant and
+/// the renames follow in line order.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ConflictPolicy {
+    /// Refuse to run anything
+    #[default]
+    Abort,
+    /// Only the first claimant gets the target, the other lines are left as they are
+    Skip,
+    /// The last claimant gets the target, replacing an existing file
+    Overwrite,
+    /// The first claimant gets the target, the others get `name (1).ext`, `name (2).ext`, ...
+    Suffix,
+    /// The last claimant gets the target and the first one is kept as `name~`
+    Backup,
+    /// Ask for each conflict which of the above to apply, through the prompt of the options
+    Prompt,
+}
+
+impl ConflictPolicy {
+    pub fn from_name(name: &str) -> Option<ConflictPolicy> {
+        match name {
+            "abort" => Some(ConflictPolicy::Abort),
+            "skip" => Some(ConflictPolicy::Skip),
+            "overwrite" => Some(ConflictPolicy::Overwrite),
+            "suffix" => Some(ConflictPolicy::Suffix),
+            "backup" => Some(ConflictPolicy::Backup),
+            "prompt" => Some(ConflictPolicy::Prompt),
+            _ => None,
+        }
+    }
+}
+
+/// An operation claiming its target, with the line it came from
+pub type Claim = (usize, Operation, Rename);
+
+pub struct Resolved {
+    /// The operations left to run, including renames that move an existing file aside as a backup
+    pub claims: Vec<(Operation, Rename)>,
+    /// Existing files that the operations replace
+    pub replaced: Vec<PathBuf>,
+    /// Lines given up on, which leave their files as they are
+    pub skipped: Vec<Claim>,
+}
+
+// What resolving has settled so far, carried from one pass to the next
+#[derive(Default)]
+struct Settled {
+    // Existing files moved aside to make way for a claim
+    backups: Vec<Rename>,
+    // Targets that have been given to their last claimant
+    overwritten: HashSet<PathBuf>,
+    replaced: Vec<PathBuf>,
+    skipped: Vec<Claim>,
+}
+
+/// A target claimed by more than one line, or by a line and a file that is already there
+pub struct Conflict<'a> {
+    pub target: PathBuf,
+    /// The target exists and is not being renamed away or deleted
+    pub existing: bool,
+    pub claims: Vec<&'a Claim>,
+}
+
+impl<'a> fmt::Display for Conflict<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut lines: Vec<usize> = self.claims.iter().map(|c| c.0).collect();
+        lines.sort();
+        let lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+        write!(
+            f,
+            "{}: {}{} {}",
+            self.target.display(),
+            if self.existing { "existing file, " } else { "" },
+            if lines.len() == 1 { "line" } else { "lines" },
+            lines.join(", ")
+        )
+    }
+}
+
+/// Decides what to do about a conflict under `ConflictPolicy::Prompt`, typically by asking the
+/// user. Answering `Prompt` again, or having no prompt, aborts.
+pub type Prompt = Arc<dyn Fn(&Conflict) -> ConflictPolicy + Send + Sync>;
+
+/// Applies the policy to every conflicting target. Renames, copies and links are paired with the
+/// (1-based) line they came from, and each renamed source is expected to appear only once. Files
+/// that are renamed away or deleted in the same batch do not count as being in the way, while
+/// copied and linked ones stay where they are.
+pub fn resolve(
+    fs: &dyn Filesystem,
+    renames: &[Claim],
+    deleted: &[PathBuf],
+    policy: ConflictPolicy,
+    prompt: Option<&Prompt>,
+) -> Result<Resolved, Error> {
+    let mut claims: Vec<Claim> = renames.to_vec();
+    let mut settled = Settled::default();
+
+    // A skipped rename leaves its source in place, which can turn it into an existing file in
+    // the way of another rename, so conflicts are resolved until nothing else is dropped.
+    loop {
+        let before = claims.len();
+        claims = resolve_once(fs, &claims, deleted, policy, prompt, &mut settled)?;
+        if claims.len() == before {
+            break;
+        }
+    }
+
+    let mut resolved: Vec<(Operation, Rename)> =
+        claims.into_iter().map(|(_, op, r)| (op, r)).collect();
+    resolved.extend(settled.backups.into_iter().map(|r| (Operation::Rename, r)));
+    settled.skipped.sort_by_key(|c| c.0);
+    Ok(Resolved {
+        claims: resolved,
+        replaced: settled.replaced,
+        skipped: settled.skipped,
+    })
+}
+
+fn resolve_once(
+    fs: &dyn Filesystem,
+    claims: &[Claim],
+    deleted: &[PathBuf],
+    policy: ConflictPolicy,
+    prompt: Option<&Prompt>,
+    settled: &mut Settled,
+) -> Result<Vec<Claim>, Error> {
+    let Settled {
+        ref mut backups,
+        ref mut overwritten,
+        ref mut replaced,
+        ref mut skipped,
+    } = *settled;
+    let sources: HashSet<PathBuf> = claims
+        .iter()
+        .filter(|c| c.1.vacates())
+        .map(|c| &c.2)
+        .chain(backups.iter())
+        .map(|r| normalize(&r.from))
+        .chain(deleted.iter().map(|path| normalize(path)))
+        .collect();
+    let mut taken: HashSet<PathBuf> = claims
+        .iter()
+        .map(|c| &c.2)
+        .chain(backups.iter())
+        .map(|r| normalize(&r.to))
+        .collect();
+
+    let mut conflicts: Vec<Conflict> = vec![];
+    let mut by_target: HashMap<PathBuf, usize> = HashMap::new();
+    for claim in claims {
+        let target = normalize(&claim.2.to);
+        if let Some(&i) = by_target.get(&target) {
+            conflicts[i].claims.push(claim);
+            continue;
+        }
+
+        by_target.insert(target.clone(), conflicts.len());
+        conflicts.push(Conflict {
+            existing: !sources.contains(&target)
+                && !overwritten.contains(&target)
+                && fs.exists(&target),
+            target,
+            claims: vec![claim],
+        });
+    }
+
+    let mut resolved: Vec<Claim> = vec![];
+    let mut blocked: Vec<String> = vec![];
+    let mut blocked_lines: Vec<usize> = vec![];
+
+    for conflict in conflicts {
+        let claimants = conflict.claims.len() + if conflict.existing { 1 } else { 0 };
+        if claimants < 2 {
+            resolved.push(conflict.claims[0].clone());
+            continue;
+        }
+
+        let action = match (policy, prompt) {
+            (ConflictPolicy::Prompt, Some(ask)) => ask(&conflict),
+            (policy, _) => policy,
+        };
+
+        let claims = &conflict.claims;
+        let last = claims[claims.len() - 1];
+        match action {
+            ConflictPolicy::Abort | ConflictPolicy::Prompt => {
+                blocked.push(format!("\n  {}", conflict));
+                blocked_lines.extend(claims.iter().map(|c| c.0));
+            }
+            ConflictPolicy::Skip => {
+                let rest = if conflict.existing {
+                    &claims[..]
+                } else {
+                    resolved.push(claims[0].clone());
+                    &claims[1..]
+                };
+                skipped.extend(rest.iter().map(|&c| c.clone()));
+            }
+            // Lines that lose the target to a later one are left as they are
+            ConflictPolicy::Overwrite => {
+                if conflict.existing {
+                    replaced.push(conflict.target.clone());
+                }
+                overwritten.insert(conflict.target.clone());
+                resolved.push(last.clone());
+                skipped.extend(claims[..claims.len() - 1].iter().map(|&c| c.clone()));
+            }
+            ConflictPolicy::Suffix => {
+                let rest = if conflict.existing {
+                    &claims[..]
+                } else {
+                    resolved.push(claims[0].clone());
+                    &claims[1..]
+                };
+                for claim in rest {
+                    let name = free_name(fs, &conflict.target, &mut taken);
+                    resolved.push((claim.0, claim.1, claim.2.with_to(name)));
+                }
+            }
+            ConflictPolicy::Backup => {
+                // Only the latest backup is kept
+                let backup = backup_name(&conflict.target);
+                if !sources.contains(&backup)
+                    && !replaced.contains(&backup)
+                    && fs.exists(&backup)
+                {
+                    replaced.push(backup.clone());
+                }
+                let losing = if conflict.existing {
+                    backups.push(Rename::new(&conflict.target, &backup));
+                    &claims[..claims.len() - 1]
+                } else {
+                    resolved.push((claims[0].0, claims[0].1, claims[0].2.with_to(&backup)));
+                    &claims[1..claims.len() - 1]
+                };
+                resolved.push(last.clone());
+                skipped.extend(losing.iter().map(|&c| c.clone()));
+            }
+        }
+    }
+
+    if blocked.is_empty() {
+        Ok(resolved)
+    } else {
+        blocked_lines.sort();
+        Err(Error::conflict(
+            blocked_lines,
+            format!("Refusing to overwrite, conflicting targets:{}", blocked.concat()),
+        ))
+    }
+}
+
+/// Compare by components so that `./a/b`, `a/./b` and `a/b/` name the same target
+pub fn normalize(path: &Path) -> PathBuf {
+    path.components().filter(|&c| c != Component::CurDir).collect()
+}
+
+fn backup_name(target: &Path) -> PathBuf {
+    let mut name = target.as_os_str().to_owned();
+    name.push("~");
+    PathBuf::from(name)
+}
+
+// The first of `name (1).ext`, `name (2).ext`, ... that neither exists nor is claimed already
+fn free_name(fs: &dyn Filesystem, target: &Path, taken: &mut HashSet<PathBuf>) -> PathBuf {
+    let stem = target.file_stem().unwrap_or_default();
+
+    for n in 1.. {
+        let mut name = OsString::from(stem);
+        name.push(format!(" ({})", n));
+        if let Some(ext) = target.extension() {
+            name.push(".");
+            name.push(ext);
+        }
+
+        let candidate = target.with_file_name(name);
+        if !taken.contains(&candidate) && !fs.exists(&candidate) {
+            taken.insert(candidate.clone());
+            return candidate;
+        }
+    }
+
+    unreachable!()
+}
@@ -0,0 +1,293 @@
+use std::fs::{self, File, FileTimes, Metadata, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use libc;
+use uuid::Uuid;
+use walkdir::WalkDir;
+
+use filesystem::Progress;
+use rename::TEMP_PREFIX;
+
+// Trees with at least this many entries report their progress while being copied
+const PROGRESS_THRESHOLD: usize = 1000;
+const PROGRESS_EVERY: usize = 500;
+
+// Moves a file or directory to another filesystem, where `fs::rename` fails with EXDEV. The copy
+// is staged under a temporary name next to the target and renamed into place, which replaces the
+// target the same way `fs::rename` would. The source is only removed once the copy is complete
+// and matches it.
+pub fn move_across_devices(from: &Path, to: &Path, progress: &Progress) -> io::Result<()> {
+    let staging = to.with_file_name(format!("{}{}", TEMP_PREFIX, Uuid::new_v4()));
+
+    copy_tree(from, &staging, progress)?;
+
+    if let Err(e) = verify(from, &staging).and_then(|_| fs::rename(&staging, to)) {
+        let _ = remove(&staging);
+        return Err(e);
+    }
+
+    remove(from)
+}
+
+// Copies a file, symlink or whole directory tree, keeping permissions, timestamps and, where
+// allowed, ownership. Symlinks are copied as links rather than followed. A partial copy is
+// removed again on failure. Trees with many entries tell `progress` how far they have got.
+pub fn copy_tree(from: &Path, to: &Path, progress: &Progress) -> io::Result<()> {
+    if fs::symlink_metadata(to).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", to.display()),
+        ));
+    }
+
+    let result = copy_entries(from, to, progress);
+    if result.is_err() {
+        let _ = remove(to);
+    }
+    result
+}
+
+fn copy_entries(from: &Path, to: &Path, progress: &Progress) -> io::Result<()> {
+    let md = fs::symlink_metadata(from)?;
+    if !md.is_dir() {
+        return copy_entry(from, to, &md);
+    }
+
+    let total = WalkDir::new(from).into_iter().count();
+    let mut dirs = vec![];
+
+    for (n, entry) in WalkDir::new(from).follow_links(false).into_iter().enumerate() {
+        let entry = entry?;
+        let dest = match entry.path().strip_prefix(from) {
+            Ok(relative) => to.join(relative),
+            Err(_) => continue,
+        };
+        let md = entry.metadata()?;
+
+        if md.is_dir() {
+            fs::create_dir(&dest)?;
+            dirs.push((dest, md));
+        } else {
+            copy_entry(entry.path(), &dest, &md)?;
+        }
+
+        if total >= PROGRESS_THRESHOLD && (n + 1) % PROGRESS_EVERY == 0 {
+            progress(from, n + 1, total);
+        }
+    }
+
+    // Directories get their permissions and timestamps last, since filling them changes their
+    // timestamps and a read-only directory cannot be filled
+    for (dir, md) in dirs.iter().rev() {
+        set_owner(dir, md);
+        fs::set_permissions(dir, md.permissions())?;
+        set_times(dir, md)?;
+    }
+
+    Ok(())
+}
+
+fn copy_entry(from: &Path, to: &Path, md: &Metadata) -> io::Result<()> {
+    if md.file_type().is_symlink() {
+        symlink(&fs::read_link(from)?, to)?;
+        set_owner(to, md);
+        return Ok(());
+    }
+    if !md.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Cannot copy special file {}", from.display()),
+        ));
+    }
+
+    if reflink(from, to).is_err() {
+        fs::copy(from, to)?;
+    }
+    // Changing the owner clears the setuid and setgid bits, so permissions are set after it
+    set_owner(to, md);
+    fs::set_permissions(to, md.permissions())?;
+    set_times(to, md)
+}
+
+// Clones the contents of a file on filesystems that can share data between files, such as Btrfs
+// and XFS, which takes no time and no space until one of them is changed
+#[cfg(target_os = "linux")]
+fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // _IOW(0x94, 9, int) from linux/fs.h
+    const FICLONE: u64 = 0x4004_9409;
+
+    let source = File::open(from)?;
+    let target = OpenOptions::new().write(true).create_new(true).open(to)?;
+    if unsafe { libc::ioctl(target.as_raw_fd(), FICLONE as _, source.as_raw_fd()) } == -1 {
+        let e = io::Error::last_os_error();
+        let _ = fs::remove_file(to);
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "reflinks are not supported"))
+}
+
+// Only root can give files away, so for anyone else the copy is left owned by them
+#[cfg(unix)]
+fn set_owner(path: &Path, md: &Metadata) {
+    use std::os::unix::fs::MetadataExt;
+
+    let _ = ::std::os::unix::fs::lchown(path, Some(md.uid()), Some(md.gid()));
+}
+
+#[cfg(not(unix))]
+fn set_owner(_path: &Path, _md: &Metadata) {}
+
+fn set_times(path: &Path, md: &Metadata) -> io::Result<()> {
+    let times = FileTimes::new()
+        .set_accessed(md.accessed()?)
+        .set_modified(md.modified()?);
+    File::open(path)?.set_times(times)
+}
+
+#[cfg(unix)]
+pub fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    ::std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+pub fn symlink(_target: &Path, link: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!("Cannot create symlink {}", link.display()),
+    ))
+}
+
+// Checks that every entry of the source has a counterpart of the same type and size in the copy
+fn verify(from: &Path, to: &Path) -> io::Result<()> {
+    for entry in WalkDir::new(from).follow_links(false) {
+        let entry = entry?;
+        let dest = match entry.path().strip_prefix(from) {
+            Ok(relative) if relative.as_os_str().is_empty() => to.to_path_buf(),
+            Ok(relative) => to.join(relative),
+            Err(_) => continue,
+        };
+
+        let expected = entry.metadata()?;
+        let copied = fs::symlink_metadata(&dest)?;
+
+        let matches = if expected.file_type().is_symlink() {
+            copied.file_type().is_symlink()
+                && fs::read_link(entry.path())? == fs::read_link(&dest)?
+        } else {
+            expected.file_type() == copied.file_type()
+                && (expected.is_dir() || expected.len() == copied.len())
+        };
+
+        if !matches {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Copy of {} does not match the original", entry.path().display()),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(name);
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn move_tree_test() {
+        let dir = temp_dir("copy_move_tree_test");
+        let from = dir.join("from");
+
+        fs::create_dir_all(from.join("sub")).unwrap();
+        fs::write(from.join("sub/x.txt"), "x").unwrap();
+        fs::set_permissions(from.join("sub/x.txt"), fs::Permissions::from_mode(0o640)).unwrap();
+        symlink("sub/x.txt", from.join("link")).unwrap();
+        fs::set_permissions(from.join("sub"), fs::Permissions::from_mode(0o555)).unwrap();
+
+        let mtime = fs::metadata(from.join("sub/x.txt")).unwrap().modified().unwrap();
+        let to = dir.join("to");
+
+        super::move_across_devices(&from, &to, &|_, _, _| ()).unwrap();
+
+        assert!(!from.exists());
+        assert_eq!(fs::read_to_string(to.join("link")).unwrap(), "x");
+        assert_eq!(fs::read_link(to.join("link")).unwrap(), PathBuf::from("sub/x.txt"));
+
+        let md = fs::metadata(to.join("sub/x.txt")).unwrap();
+        assert_eq!(md.permissions().mode() & 0o777, 0o640);
+        assert_eq!(md.modified().unwrap(), mtime);
+
+        let md = fs::metadata(to.join("sub")).unwrap();
+        assert_eq!(md.permissions().mode() & 0o777, 0o555);
+
+        fs::set_permissions(to.join("sub"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn copy_tree_test() {
+        let dir = temp_dir("copy_tree_test");
+        let from = dir.join("from");
+
+        fs::create_dir_all(from.join("sub")).unwrap();
+        fs::write(from.join("sub/x.txt"), "x").unwrap();
+        fs::set_permissions(from.join("sub/x.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+        let mtime = fs::metadata(from.join("sub/x.txt")).unwrap().modified().unwrap();
+
+        super::copy_tree(&from, &dir.join("to"), &|_, _, _| ()).unwrap();
+
+        assert_eq!(fs::read_to_string(from.join("sub/x.txt")).unwrap(), "x");
+        assert_eq!(fs::read_to_string(dir.join("to/sub/x.txt")).unwrap(), "x");
+        let md = fs::metadata(dir.join("to/sub/x.txt")).unwrap();
+        assert_eq!(md.permissions().mode() & 0o777, 0o600);
+        assert_eq!(md.modified().unwrap(), mtime);
+
+        // Copies never replace anything
+        let (file, copied) = (from.join("sub/x.txt"), dir.join("to/sub/x.txt"));
+        assert!(super::copy_tree(&file, &copied, &|_, _, _| ()).is_err());
+    }
+
+    #[test]
+    fn failed_move_test() {
+        let dir = temp_dir("copy_failed_move_test");
+        fs::write(dir.join("a"), "a").unwrap();
+        fs::write(dir.join("b"), "b").unwrap();
+
+        // Replaces the target like a rename would
+        super::move_across_devices(&dir.join("a"), &dir.join("b"), &|_, _, _| ()).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("b")).unwrap(), "a");
+
+        // Leaves the source and nothing else behind when the target cannot be created
+        let missing = dir.join("missing/c");
+        assert!(super::move_across_devices(&dir.join("b"), &missing, &|_, _, _| ()).is_err());
+        assert_eq!(fs::read_to_string(dir.join("b")).unwrap(), "a");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    }
+}
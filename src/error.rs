@@ -0,0 +1,92 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use rename::Rollback;
+
+/// Why a plan could not be worked out or run
+#[derive(Debug)]
+pub enum Error {
+    /// A file operation failed. The action names what was being done, path included.
+    Io {
+        action: String,
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// A line of the buffer cannot be used, counting lines from 1
+    Line { line: usize, message: String },
+    /// The buffer gives a different number of names than there are files
+    LineCount { expected: usize, found: usize },
+    /// Lines of the plan contradict each other, or their targets are in the way
+    Conflict { lines: Vec<usize>, message: String },
+    /// A step of a transactional batch failed, and the steps done before it were undone as far
+    /// as they could be
+    RolledBack { error: Box<Error>, rollback: Rollback },
+    /// A journal batch cannot be read, undone or recovered
+    Batch(String),
+    /// Asked whether to go ahead, the user declined
+    Cancelled,
+}
+
+impl Error {
+    pub fn io<S: Into<String>, P: Into<PathBuf>>(action: S, path: P, error: io::Error) -> Error {
+        Error::Io {
+            action: action.into(),
+            path: path.into(),
+            error,
+        }
+    }
+
+    pub fn line<S: Into<String>>(line: usize, message: S) -> Error {
+        Error::Line {
+            line,
+            message: message.into(),
+        }
+    }
+
+    pub fn conflict<S: Into<String>>(lines: Vec<usize>, message: S) -> Error {
+        Error::Conflict {
+            lines,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io {
+                ref action,
+                ref error,
+                ..
+            } => write!(f, "Failed to {}: {}", action, error),
+            Error::Line { line, ref message } => {
+                write!(f, "Line {} of the buffer {}", line, message)
+            }
+            Error::LineCount { expected, found } => write!(
+                f,
+                "The buffer gives {} names for {} files, renamed files do not match the originals",
+                found, expected
+            ),
+            Error::Conflict { ref message, .. } | Error::Batch(ref message) => {
+                write!(f, "{}", message)
+            }
+            Error::RolledBack {
+                ref error,
+                ref rollback,
+            } => write!(f, "{}\n{}", error, rollback),
+            Error::Cancelled => write!(f, "Cancelled, nothing has been changed"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io { ref error, .. } => Some(error),
+            Error::RolledBack { ref error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::ffi::{OsStr, OsString};
+
+/// Paths are written out one per line, to the journal and to the editor buffer. Separators and
+/// control characters are written as C-style escapes, and so is every byte that is not part of
+/// valid UTF-8, as `\xNN`. Unescaping gives back the exact original bytes.
+pub fn escape(s: &OsStr) -> String {
+    let bytes = as_bytes(s);
+    let mut escaped = String::with_capacity(bytes.len());
+
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '\t' => escaped.push_str("\\t"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                c if c.is_ascii_control() => escaped.push_str(&format!("\\x{:02x}", c as u8)),
+                c => escaped.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+
+    escaped
+}
+
+/// Unknown escapes are kept as the character after the backslash
+pub fn unescape(s: &[u8]) -> OsString {
+    let mut unescaped: Vec<u8> = Vec::with_capacity(s.len());
+    let mut i = 0;
+
+    while i < s.len() {
+        if s[i] != b'\\' || i + 1 == s.len() {
+            unescaped.push(s[i]);
+            i += 1;
+            continue;
+        }
+
+        let (byte, len) = match s[i + 1] {
+            b't' => (b'\t', 2),
+            b'n' => (b'\n', 2),
+            b'r' => (b'\r', 2),
+            b'x' => match s.get(i + 2..i + 4).and_then(hex_byte) {
+                Some(b) => (b, 4),
+                None => (b'x', 2),
+            },
+            b => (b, 2),
+        };
+        unescaped.push(byte);
+        i += len;
+    }
+
+    from_bytes(unescaped)
+}
+
+/// Names in the editor buffer are written as they are, unless that would lose bytes or hide part
+/// of the name. Those that are not valid UTF-8, contain control characters, start with a quote, or
+/// have a component starting or ending with whitespace are put in double quotes, with the escapes
+/// of `escape` inside.
+pub fn quote(s: &OsStr) -> String {
+    match s.to_str() {
+        Some(name) if !needs_quotes(name) => name.to_string(),
+        _ => format!("\"{}\"", escape(s).replace('"', "\\\"")),
+    }
+}
+
+/// Lines that are not in quotes are taken as they are
+pub fn unquote(line: &[u8]) -> OsString {
+    if line.len() >= 2 && line[0] == b'"' && line[line.len() - 1] == b'"' {
+        unescape(&line[1..line.len() - 1])
+    } else {
+        from_bytes(line.to_vec())
+    }
+}
+
+fn needs_quotes(name: &str) -> bool {
+    name.starts_with('"')
+        || name.chars().any(char::is_control)
+        || name.split('/').any(|component| {
+            component.starts_with(char::is_whitespace) || component.ends_with(char::is_whitespace)
+        })
+}
+
+fn hex_byte(digits: &[u8]) -> Option<u8> {
+    ::std::str::from_utf8(digits)
+        .ok()
+        .and_then(|d| u8::from_str_radix(d, 16).ok())
+}
+
+#[cfg(unix)]
+pub fn as_bytes(s: &OsStr) -> &[u8] {
+    use std::os::unix::ffi::OsStrExt;
+    s.as_bytes()
+}
+
+#[cfg(not(unix))]
+pub fn as_bytes(s: &OsStr) -> &[u8] {
+    s.as_encoded_bytes()
+}
+
+#[cfg(unix)]
+pub fn from_bytes(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+pub fn from_bytes(bytes: Vec<u8>) -> OsString {
+    OsString::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape, from_bytes, quote, unescape, unquote};
+    use std::ffi::OsStr;
+
+    #[test]
+    fn escape_test() {
+        for s in &["plain.txt", "tab\there", "new\nline", "back\\slash\\t", "bell\x07"] {
+            let escaped = escape(OsStr::new(s));
+            assert_eq!(unescape(escaped.as_bytes()), OsStr::new(s));
+            assert!(!escaped.contains('\t'));
+            assert!(!escaped.contains('\n'));
+        }
+
+        // Latin-1 `café.txt` is not valid UTF-8
+        let latin1 = from_bytes(b"caf\xe9.txt".to_vec());
+        assert_eq!(escape(&latin1), "caf\\xe9.txt");
+        assert_eq!(unescape(b"caf\\xe9.txt"), latin1);
+        assert_eq!(escape(OsStr::new("café.txt")), "café.txt");
+    }
+
+    #[test]
+    fn quote_test() {
+        // Only names that need it are quoted
+        assert_eq!(quote(OsStr::new("dir/back\\slash.txt")), "dir/back\\slash.txt");
+        assert_eq!(quote(OsStr::new("dir/ lead.txt")), "\"dir/ lead.txt\"");
+        assert_eq!(quote(OsStr::new("trail /x.txt")), "\"trail /x.txt\"");
+        assert_eq!(quote(OsStr::new("new\nline")), "\"new\\nline\"");
+        assert_eq!(quote(OsStr::new("\"quoted\"")), "\"\\\"quoted\\\"\"");
+
+        let latin1 = from_bytes(b"caf\xe9.txt".to_vec());
+        assert_eq!(quote(&latin1), "\"caf\\xe9.txt\"");
+
+        for name in &[
+            OsStr::new("plain.txt"),
+            OsStr::new("back\\slash\\n"),
+            OsStr::new(" lead"),
+            OsStr::new("trail\t"),
+            OsStr::new("new\nline"),
+            OsStr::new("\""),
+            OsStr::new("say \"hi\""),
+            &latin1,
+        ] {
+            let quoted = quote(name);
+            assert!(!quoted.contains('\n'));
+            assert_eq!(unquote(quoted.as_bytes()), *name);
+        }
+    }
+}
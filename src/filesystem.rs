@@ -0,0 +1,370 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use libc;
+use uuid::Uuid;
+
+use copy;
+use rename::TEMP_PREFIX;
+use trash;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// What the planner needs to know about a file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileInfo {
+    pub kind: FileKind,
+    /// Permission bits, along with the setuid, setgid and sticky bits
+    pub mode: u32,
+    /// The device and inode tell files apart, and hard links to the same file alike. An inode of
+    /// 0 means that the system has none to give.
+    pub dev: u64,
+    pub ino: u64,
+    pub uid: u32,
+    pub gid: u32,
+    /// The length of a file's contents, or of what a symlink points to
+    pub size: u64,
+}
+
+impl FileInfo {
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileKind::Dir
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.kind == FileKind::Symlink
+    }
+
+    /// Whether both describe the same file, such as two hard links to it
+    pub fn is_same_file(&self, other: &FileInfo) -> bool {
+        self.ino != 0 && self.dev == other.dev && self.ino == other.ino
+    }
+}
+
+/// Who changes the files, which decides what they are allowed to change
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct User {
+    pub uid: u32,
+    /// The primary group along with the supplementary ones
+    pub groups: Vec<u32>,
+}
+
+impl User {
+    /// Root may change anything
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+}
+
+/// Everything the planner looks up and the executor changes goes through a filesystem, so that a
+/// plan can be worked out and run against a tree other than the real one. Filesystems can be
+/// shared between threads, so that plans holding one can be sent to another thread.
+pub trait Filesystem: Send + Sync {
+    /// Describes a file, or a symlink itself rather than what it points to
+    fn symlink_metadata(&self, path: &Path) -> io::Result<FileInfo>;
+    /// Describes a file, following symlinks
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo>;
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+    /// The entries of a directory, as paths under it
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+    fn current_dir(&self) -> io::Result<PathBuf>;
+    fn user(&self) -> User;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    /// Renames a file, only replacing an existing target when `replace` is set
+    fn rename(&self, from: &Path, to: &Path, replace: bool) -> io::Result<()>;
+    /// Swaps two files
+    fn exchange(&self, a: &Path, b: &Path) -> io::Result<()>;
+    /// Copies a file or a whole directory tree to a name that is not taken yet
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()>;
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    /// Removes an empty directory
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+    /// Moves a file to the name picked for it in a trash, recording where it came from
+    fn trash(&self, path: &Path, trashed: &Path) -> io::Result<()>;
+    fn restore(&self, trashed: &Path, path: &Path) -> io::Result<()>;
+
+    fn exists(&self, path: &Path) -> bool {
+        self.symlink_metadata(path).is_ok()
+    }
+}
+
+// Told how far the copy of a large tree has got, as the tree, the entries copied and their total
+pub type Progress = dyn Fn(&Path, usize, usize) + Send + Sync;
+
+/// The filesystem of the running system
+#[derive(Clone, Default)]
+pub struct RealFs {
+    progress: Option<Arc<Progress>>,
+}
+
+impl RealFs {
+    pub fn new() -> Self {
+        RealFs::default()
+    }
+
+    /// Reports the progress of copies, including those that move files to another filesystem
+    pub fn on_progress<F>(mut self, progress: F) -> Self
+    where
+        F: Fn(&Path, usize, usize) + Send + Sync + 'static,
+    {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    fn progress(&self) -> &Progress {
+        match self.progress {
+            Some(ref progress) => &**progress,
+            None => &|_, _, _| (),
+        }
+    }
+}
+
+impl Filesystem for RealFs {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        fs::symlink_metadata(path).map(|md| info(&md))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        fs::metadata(path).map(|md| info(&md))
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect()
+    }
+
+    fn current_dir(&self) -> io::Result<PathBuf> {
+        env::current_dir()
+    }
+
+    fn user(&self) -> User {
+        current_user()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    // Files on different filesystems are moved by copying them across
+    fn rename(&self, from: &Path, to: &Path, replace: bool) -> io::Result<()> {
+        let result = if replace {
+            fs::rename(from, to)
+        } else {
+            rename_noreplace(from, to)
+        };
+        match result {
+            Err(ref e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                if !replace && self.exists(to) {
+                    return Err(already_exists(to));
+                }
+                copy::move_across_devices(from, to, self.progress())
+            }
+            result => result,
+        }
+    }
+
+    // Swaps two files in a single atomic step where the filesystem supports it, and otherwise
+    // through a temporary name next to the first file
+    fn exchange(&self, a: &Path, b: &Path) -> io::Result<()> {
+        match rename_exchange(a, b) {
+            Err(ref e) if is_unsupported(e) || e.kind() == io::ErrorKind::CrossesDevices => {
+                let temp_path = a.with_file_name(format!("{}{}", TEMP_PREFIX, Uuid::new_v4()));
+
+                self.rename(a, &temp_path, false)?;
+                if let Err(e) = self.rename(b, a, false) {
+                    let _ = self.rename(&temp_path, a, false);
+                    return Err(e);
+                }
+                self.rename(&temp_path, b, false)
+            }
+            result => result,
+        }
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        copy::copy_tree(from, to, self.progress())
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        copy::symlink(target, link)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::hard_link(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(path)
+    }
+
+    fn trash(&self, path: &Path, trashed: &Path) -> io::Result<()> {
+        trash::put(self, path, trashed)
+    }
+
+    fn restore(&self, trashed: &Path, path: &Path) -> io::Result<()> {
+        trash::restore(trashed, path)
+    }
+}
+
+fn kind(md: &fs::Metadata) -> FileKind {
+    if md.file_type().is_symlink() {
+        FileKind::Symlink
+    } else if md.is_dir() {
+        FileKind::Dir
+    } else {
+        FileKind::File
+    }
+}
+
+#[cfg(unix)]
+fn info(md: &fs::Metadata) -> FileInfo {
+    use std::os::unix::fs::MetadataExt;
+
+    FileInfo {
+        kind: kind(md),
+        mode: md.mode() & 0o7777,
+        dev: md.dev(),
+        ino: md.ino(),
+        uid: md.uid(),
+        gid: md.gid(),
+        size: md.len(),
+    }
+}
+
+// Without unix permissions, files are taken to belong to the user and to be writable unless
+// they are read-only
+#[cfg(not(unix))]
+fn info(md: &fs::Metadata) -> FileInfo {
+    let mode = match (md.is_dir(), md.permissions().readonly()) {
+        (true, false) => 0o755,
+        (true, true) => 0o555,
+        (false, false) => 0o644,
+        (false, true) => 0o444,
+    };
+    FileInfo {
+        kind: kind(md),
+        mode,
+        dev: 0,
+        ino: 0,
+        uid: 0,
+        gid: 0,
+        size: md.len(),
+    }
+}
+
+#[cfg(unix)]
+fn current_user() -> User {
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let mut groups = vec![gid];
+    let count = unsafe { libc::getgroups(0, ::std::ptr::null_mut()) };
+    if count > 0 {
+        let mut supplementary: Vec<libc::gid_t> = vec![0; count as usize];
+        let count = unsafe { libc::getgroups(count, supplementary.as_mut_ptr()) };
+        supplementary.truncate(count.max(0) as usize);
+        groups.extend(supplementary);
+    }
+    User { uid, groups }
+}
+
+// Matching the files, which all belong to the user
+#[cfg(not(unix))]
+fn current_user() -> User {
+    User::default()
+}
+
+#[cfg(target_os = "linux")]
+fn rename_noreplace(from: &Path, to: &Path) -> io::Result<()> {
+    match renameat2(from, to, libc::RENAME_NOREPLACE) {
+        Err(ref e) if is_unsupported(e) => checked_rename(from, to),
+        result => result,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rename_noreplace(from: &Path, to: &Path) -> io::Result<()> {
+    checked_rename(from, to)
+}
+
+// Where the kernel cannot refuse to replace the target by itself, the target is checked just
+// before the rename instead
+fn checked_rename(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(to).is_ok() {
+        return Err(already_exists(to));
+    }
+    fs::rename(from, to)
+}
+
+#[cfg(target_os = "linux")]
+fn rename_exchange(a: &Path, b: &Path) -> io::Result<()> {
+    renameat2(a, b, libc::RENAME_EXCHANGE)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rename_exchange(_a: &Path, _b: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "renameat2 is not available"))
+}
+
+#[cfg(target_os = "linux")]
+fn renameat2(from: &Path, to: &Path, flags: libc::c_uint) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let from = CString::new(from.as_os_str().as_bytes())?;
+    let to = CString::new(to.as_os_str().as_bytes())?;
+    // Called through syscall(2), as older C libraries have no wrapper for it
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            from.as_ptr(),
+            libc::AT_FDCWD,
+            to.as_ptr(),
+            flags,
+        )
+    };
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// Kernels before 3.15 do not know renameat2, and not every filesystem supports its flags
+fn is_unsupported(e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(libc::ENOSYS) | Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) => true,
+        _ => e.kind() == io::ErrorKind::Unsupported,
+    }
+}
+
+fn already_exists(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("{} already exists", path.display()),
+    )
+}
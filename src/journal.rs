@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use escape::{escape, unescape};
+use filesystem::{FileInfo, Filesystem};
+use rename::{Rename, Step};
+
+const EXTENSION: &str = "journal";
+
+// A batch of steps as it was planned, temporary moves included. Steps are marked as they
+// complete, so the log doubles as an intent log if the process is interrupted, and replaying the
+// completed steps backwards restores the original names.
+#[derive(Clone)]
+pub struct Batch {
+    pub id: String,
+    pub time: u64,
+    pub cwd: PathBuf,
+    pub steps: Vec<Step>,
+    pub done: Vec<bool>,
+    // The device and inode of the file at the source of an exchange, by step, recorded just
+    // before it runs
+    pub exchanged: HashMap<usize, (u64, u64)>,
+    pub complete: bool,
+    pub undone: bool,
+}
+
+impl Batch {
+    pub fn completed(&self) -> Vec<Step> {
+        self.steps
+            .iter()
+            .zip(self.done.iter())
+            .filter(|&(_, &done)| done)
+            .map(|(r, _)| r.clone())
+            .collect()
+    }
+}
+
+// An open batch that progress is appended to while it runs
+pub struct Log {
+    batch: Batch,
+    file: File,
+    // Progress that could not be recorded, which leaves the batch running
+    warnings: Vec<String>,
+}
+
+impl Log {
+    pub fn id(&self) -> &str {
+        &self.batch.id
+    }
+
+    pub fn step_done(&mut self, step: &Step) -> io::Result<()> {
+        match self.position(step, false) {
+            Some(i) => {
+                self.batch.done[i] = true;
+                writeln!(self.file, "done {}", i)
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Both names are still there after an exchange, so which file was at its source is
+    // recorded, and synced, before it runs
+    pub fn exchange_started(&mut self, step: &Step, source: &FileInfo) -> io::Result<()> {
+        match self.position(step, false) {
+            Some(i) => {
+                self.batch.exchanged.insert(i, (source.dev, source.ino));
+                writeln!(self.file, "exchanging {} {} {}", i, source.dev, source.ino)?;
+                self.file.sync_data()
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn step_undone(&mut self, step: &Step) -> io::Result<()> {
+        match self.position(step, true) {
+            Some(i) => {
+                self.batch.done[i] = false;
+                writeln!(self.file, "undo {}", i)
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn warn(&mut self, warning: String) {
+        self.warnings.push(warning);
+    }
+
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        ::std::mem::take(&mut self.warnings)
+    }
+
+    pub fn complete(mut self) -> io::Result<()> {
+        writeln!(self.file, "complete")?;
+        self.file.sync_all()
+    }
+
+    fn position(&self, step: &Step, done: bool) -> Option<usize> {
+        self.batch
+            .steps
+            .iter()
+            .zip(self.batch.done.iter())
+            .position(|(s, &d)| s == step && d == done)
+    }
+}
+
+/// Journals are kept under $XDG_STATE_HOME/rename, falling back to ~/.local/state/rename
+pub fn default_dir() -> Option<PathBuf> {
+    match env::var_os("XDG_STATE_HOME") {
+        Some(ref state) if Path::new(state).is_absolute() => Some(Path::new(state).join("rename")),
+        _ => env::var_os("HOME").map(|home| Path::new(&home).join(".local/state/rename")),
+    }
+}
+
+// Writes the full plan of a batch before any of it runs, with paths resolved against the current
+// directory of the filesystem it runs on
+pub fn begin(fs: &dyn Filesystem, dir: &Path, steps: &[Step]) -> io::Result<Log> {
+    fs::create_dir_all(dir)?;
+
+    let time = now();
+    let stamp = timestamp(time);
+    let mut id = stamp.clone();
+    let mut n = 0;
+    while batch_path(dir, &id).exists() {
+        n += 1;
+        id = format!("{}-{}", stamp, n);
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(batch_path(dir, &id))?;
+
+    // Paths are stored absolute so the batch can be replayed from any directory
+    let cwd = fs.current_dir()?;
+    let absolute = |p: &Path| escape(cwd.join(p).as_os_str());
+
+    writeln!(file, "time {}", time)?;
+    writeln!(file, "cwd {}", escape(cwd.as_os_str()))?;
+    for step in steps {
+        match *step {
+            Step::Rename(ref r) => {
+                writeln!(file, "rename {}\t{}", absolute(&r.from), absolute(&r.to))?
+            }
+            Step::Delete {
+                ref path,
+                recursive,
+            } => writeln!(
+                file,
+                "{} {}",
+                if recursive { "delete-tree" } else { "delete" },
+                absolute(path)
+            )?,
+            Step::Trash {
+                ref path,
+                ref trashed,
+                recursive,
+            } => writeln!(
+                file,
+                "{} {}\t{}",
+                if recursive { "trash-tree" } else { "trash" },
+                absolute(path),
+                absolute(trashed)
+            )?,
+            Step::Restore {
+                ref trashed,
+                ref path,
+            } => writeln!(file, "restore {}\t{}", absolute(trashed), absolute(path))?,
+            // What a symlink contains is kept as it is, relative or not
+            Step::Symlink(ref r) => writeln!(
+                file,
+                "symlink {}\t{}",
+                escape(r.from.as_os_str()),
+                absolute(&r.to)
+            )?,
+            Step::Replace(ref r)
+            | Step::Exchange(ref r)
+            | Step::Copy(ref r)
+            | Step::Hardlink(ref r) => {
+                let kind = match *step {
+                    Step::Replace(_) => "replace",
+                    Step::Exchange(_) => "exchange",
+                    Step::Copy(_) => "copy",
+                    _ => "hardlink",
+                };
+                writeln!(file, "{} {}\t{}", kind, absolute(&r.from), absolute(&r.to))?
+            }
+        }
+    }
+    file.sync_all()?;
+
+    Ok(Log {
+        batch: Batch {
+            id,
+            time,
+            cwd,
+            steps: steps.to_vec(),
+            done: vec![false; steps.len()],
+            exchanged: HashMap::new(),
+            complete: false,
+            undone: false,
+        },
+        file,
+        warnings: vec![],
+    })
+}
+
+// Reopens an interrupted batch so that recovering it keeps appending to the same log
+pub fn resume(dir: &Path, batch: &Batch) -> io::Result<Log> {
+    let file = OpenOptions::new()
+        .append(true)
+        .open(batch_path(dir, &batch.id))?;
+
+    Ok(Log {
+        batch: batch.clone(),
+        file,
+        warnings: vec![],
+    })
+}
+
+pub fn mark_undone(dir: &Path, id: &str) -> io::Result<()> {
+    let mut f = OpenOptions::new().append(true).open(batch_path(dir, id))?;
+    writeln!(f, "undone {}", now())
+}
+
+// Loads the batch with the given id, or the most recent one that has not been undone yet
+pub fn load(dir: &Path, id: Option<&str>) -> Result<Batch, String> {
+    match id {
+        Some(id) => read_batch(dir, id),
+        None => {
+            let mut batches = list(dir)?;
+            batches.retain(|b| b.complete && !b.undone && b.done.contains(&true));
+            batches
+                .pop()
+                .ok_or_else(|| "No batch left to undo in the journal".to_string())
+        }
+    }
+}
+
+// Batches that never reached the end, most recent first
+pub fn interrupted(dir: &Path) -> Result<Vec<Batch>, String> {
+    let mut batches = list(dir)?;
+    batches.retain(|b| !b.complete);
+    batches.reverse();
+    Ok(batches)
+}
+
+// All batches in the journal, oldest first
+pub fn list(dir: &Path) -> Result<Vec<Batch>, String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(format!("Failed to read journal {}: {}", dir.display(), e)),
+    };
+
+    let mut batches: Vec<Batch> = vec![];
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+            continue;
+        }
+        if let Some(id) = path.file_stem().and_then(|s| s.to_str()) {
+            batches.push(read_batch(dir, id)?);
+        }
+    }
+
+    batches.sort_by(|x, y| (x.time, x.id.len(), &x.id).cmp(&(y.time, y.id.len(), &y.id)));
+    Ok(batches)
+}
+
+fn read_batch(dir: &Path, id: &str) -> Result<Batch, String> {
+    let path = batch_path(dir, id);
+    let mut contents = String::new();
+    File::open(&path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|e| format!("Failed to read journal batch {}: {}", id, e))?;
+
+    let invalid = |line: &str| format!("Invalid line in journal batch {}: {}", id, line);
+
+    let mut batch = Batch {
+        id: id.to_string(),
+        time: 0,
+        cwd: PathBuf::new(),
+        steps: vec![],
+        done: vec![],
+        exchanged: HashMap::new(),
+        complete: false,
+        undone: false,
+    };
+
+    for line in contents.lines() {
+        let mut parts = line.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some("time"), Some(time)) => batch.time = time.parse().map_err(|_| invalid(line))?,
+            (Some("cwd"), Some(cwd)) => batch.cwd = PathBuf::from(unescape(cwd.as_bytes())),
+            (Some(kind), Some(paths))
+                if [
+                    "rename", "replace", "exchange", "trash", "trash-tree", "restore", "copy",
+                    "symlink", "hardlink",
+                ].contains(&kind) =>
+            {
+                let mut paths = paths.splitn(2, '\t');
+                let (from, to) = match (paths.next(), paths.next()) {
+                    (Some(from), Some(to)) => (
+                        PathBuf::from(unescape(from.as_bytes())),
+                        PathBuf::from(unescape(to.as_bytes())),
+                    ),
+                    _ => return Err(invalid(line)),
+                };
+                batch.steps.push(match kind {
+                    "rename" => Step::Rename(Rename::new(from, to)),
+                    "replace" => Step::Replace(Rename::new(from, to)),
+                    "exchange" => Step::Exchange(Rename::new(from, to)),
+                    "copy" => Step::Copy(Rename::new(from, to)),
+                    "symlink" => Step::Symlink(Rename::new(from, to)),
+                    "hardlink" => Step::Hardlink(Rename::new(from, to)),
+                    "restore" => Step::Restore {
+                        trashed: from,
+                        path: to,
+                    },
+                    _ => Step::Trash {
+                        path: from,
+                        trashed: to,
+                        recursive: kind == "trash-tree",
+                    },
+                });
+                batch.done.push(false);
+            }
+            (Some(kind), Some(path)) if kind == "delete" || kind == "delete-tree" => {
+                batch.steps.push(Step::Delete {
+                    path: PathBuf::from(unescape(path.as_bytes())),
+                    recursive: kind == "delete-tree",
+                });
+                batch.done.push(false);
+            }
+            (Some(marker), Some(step)) if marker == "done" || marker == "undo" => {
+                match step.parse::<usize>().ok().and_then(|i| batch.done.get_mut(i)) {
+                    Some(done) => *done = marker == "done",
+                    None => return Err(invalid(line)),
+                }
+            }
+            (Some("exchanging"), Some(file)) => {
+                let numbers: Vec<u64> = file.split(' ').filter_map(|n| n.parse().ok()).collect();
+                match numbers[..] {
+                    [i, dev, ino] if (i as usize) < batch.steps.len() => {
+                        batch.exchanged.insert(i as usize, (dev, ino));
+                    }
+                    _ => return Err(invalid(line)),
+                }
+            }
+            (Some("complete"), None) => batch.complete = true,
+            (Some("undone"), _) => batch.undone = true,
+            _ => return Err(invalid(line)),
+        }
+    }
+
+    Ok(batch)
+}
+
+fn batch_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.{}", id, EXTENSION))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Formats seconds since the epoch as YYYYMMDD-HHMMSS in UTC
+fn timestamp(secs: u64) -> String {
+    let (year, month, day) = civil_date((secs / 86400) as i64);
+    let rem = secs % 86400;
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+// The year, month and day of a number of days since 1970-01-01, counting in 400 year eras from
+// 0000-03-01, see http://howardhinnant.github.io/date_algorithms.html
+pub fn civil_date(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::timestamp;
+
+    #[test]
+    fn timestamp_test() {
+        assert_eq!(timestamp(0), "19700101-000000");
+        assert_eq!(timestamp(951_782_400), "20000229-000000");
+        assert_eq!(timestamp(1_792_321_199), "20261018-105959");
+    }
+}
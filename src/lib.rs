@@ -0,0 +1,46 @@
+//! Bulk renaming of files, as done by the `rename` command.
+//!
+//! A `RenamePlan` takes one line per file, each running an operation such as a rename, copy or
+//! deletion. Validating it checks the lines for conflicts and works out the steps that carry
+//! them out, which can be previewed before they are executed.
+//!
+//! ```no_run
+//! use rename::{RenameOptions, RenamePlan};
+//!
+//! let mut plan = RenamePlan::new(RenameOptions::default());
+//! plan.rename("a.txt", "b.txt").rename("b.txt", "a.txt");
+//!
+//! let plan = plan.validate().unwrap();
+//! for line in plan.preview() {
+//!     println!("{}", line);
+//! }
+//! println!("Renamed {} files", plan.execute().unwrap().files());
+//! ```
+
+extern crate libc;
+extern crate uuid;
+extern crate walkdir;
+
+pub mod buffer;
+pub mod conflict;
+mod copy;
+mod error;
+pub mod escape;
+mod filesystem;
+mod journal;
+mod memory;
+mod plan;
+mod rename;
+mod trash;
+
+pub use conflict::ConflictPolicy;
+pub use error::Error;
+pub use filesystem::{FileInfo, FileKind, Filesystem, RealFs, User};
+pub use journal::default_dir as journal_dir;
+pub use memory::MemoryFs;
+pub use plan::{RenamePlan, ValidPlan};
+pub use rename::{
+    bulk_rename, recover, undo, Operation, Rename, RenameOptions, Report, Rollback, Step,
+    TEMP_PREFIX,
+};
+pub use trash::home_dir as trash_dir;
@@ -1,12 +1,21 @@
 extern crate clap;
-extern crate tempfile;
-extern crate uuid;
+extern crate rename;
 extern crate walkdir;
 
 mod app;
-mod rename;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgGroup};
+use rename::buffer::BufferFormat;
+use rename::{ConflictPolicy, Error, Operation, Report, Step};
+use std::path::Path;
+
+// Exit codes, so that scripts can tell why a run failed
+const EXIT_FAILED: i32 = 1;
+const EXIT_INVALID: i32 = 2;
+const EXIT_CONFLICT: i32 = 3;
+const EXIT_PARTIAL: i32 = 4;
+const EXIT_JOURNAL: i32 = 5;
+const EXIT_CANCELLED: i32 = 6;
 
 fn main() {
     let matches = App::new("rename: bulk rename")
@@ -88,18 +97,223 @@ fn main() {
                 .multiple(false)
                 .help("Rename in subdirectories recursively"),
         )
+        .arg(
+            Arg::with_name("numbered")
+                .short("N")
+                .long("numbered")
+                .required(false)
+                .multiple(false)
+                .help("Number the lines in the editor, so they can be reordered or removed"),
+        )
+        .arg(
+            Arg::with_name("commands")
+                .short("c")
+                .long("commands")
+                .required(false)
+                .multiple(false)
+                .help("Number the lines and start each with a command - r, c, l, h, d or k"),
+        )
+        .group(ArgGroup::with_name("numbering").args(&["numbered", "commands"]))
+        .arg(
+            Arg::with_name("delete")
+                .short("d")
+                .long("delete")
+                .required(false)
+                .multiple(false)
+                .requires("numbering")
+                .help("Delete files whose line is removed from the numbered buffer"),
+        )
+        .arg(
+            Arg::with_name("recursive-delete")
+                .long("recursive-delete")
+                .required(false)
+                .multiple(false)
+                .requires("delete")
+                .help("Delete directories along with their contents, not only empty ones"),
+        )
+        .arg(
+            Arg::with_name("copy")
+                .long("copy")
+                .required(false)
+                .multiple(false)
+                .help("Copy files to their edited names, leaving the originals in place"),
+        )
+        .arg(
+            Arg::with_name("symlink")
+                .long("symlink")
+                .required(false)
+                .multiple(false)
+                .help("Make symlinks to the files under their edited names"),
+        )
+        .arg(
+            Arg::with_name("hardlink")
+                .long("hardlink")
+                .required(false)
+                .multiple(false)
+                .help("Make hard links to the files under their edited names"),
+        )
+        .group(ArgGroup::with_name("operation").args(&["copy", "symlink", "hardlink"]))
+        .arg(
+            Arg::with_name("relative")
+                .long("relative")
+                .required(false)
+                .multiple(false)
+                .help("Point symlinks to their files by relative paths"),
+        )
+        .arg(
+            Arg::with_name("unlink")
+                .long("unlink")
+                .required(false)
+                .multiple(false)
+                .help("Remove deleted and overwritten files instead of moving them to the trash"),
+        )
+        .arg(
+            Arg::with_name("transactional")
+                .short("T")
+                .long("transactional")
+                .required(false)
+                .multiple(false)
+                .help("Undo all renames if any of them fails"),
+        )
+        .arg(
+            Arg::with_name("rename-dirs")
+                .short("D")
+                .long("rename-dirs")
+                .required(false)
+                .multiple(false)
+                .help("Rename parent directories changed on a line instead of moving the file"),
+        )
+        .arg(
+            Arg::with_name("prune")
+                .short("p")
+                .long("prune")
+                .required(false)
+                .multiple(false)
+                .help("Remove directories left empty after moving files out of them"),
+        )
+        .arg(
+            Arg::with_name("overwrite")
+                .long("overwrite")
+                .required(false)
+                .multiple(false)
+                .conflicts_with("on-conflict")
+                .help("Allow renaming over existing files, same as --on-conflict overwrite"),
+        )
+        .arg(
+            Arg::with_name("on-conflict")
+                .long("on-conflict")
+                .required(false)
+                .takes_value(true)
+                .multiple(false)
+                .value_name("policy")
+                .possible_values(&["abort", "skip", "overwrite", "suffix", "backup", "prompt"])
+                .help(
+                    "Specify what to do when targets collide or already exist - \
+                     abort (default), skip, overwrite, suffix, backup or prompt",
+                ),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .required(false)
+                .multiple(false)
+                .help("Show the steps and simulate them to predict failures, changing nothing"),
+        )
+        .arg(
+            Arg::with_name("undo")
+                .long("undo")
+                .required(false)
+                .takes_value(true)
+                .min_values(0)
+                .max_values(1)
+                .value_name("id")
+                .help("Undo the last batch of renames, or the batch with the given id"),
+        )
+        .arg(
+            Arg::with_name("recover")
+                .long("recover")
+                .required(false)
+                .takes_value(true)
+                .min_values(0)
+                .max_values(1)
+                .possible_values(&["rollback", "finish"])
+                .value_name("action")
+                .help("Roll back (default) or finish batches interrupted part way"),
+        )
         .get_matches();
 
+    let journal_dir = rename::journal_dir();
+
+    if matches.is_present("undo") || matches.is_present("recover") {
+        let fs = app::real_fs();
+        let result = match journal_dir {
+            Some(ref dir) if matches.is_present("undo") => {
+                rename::undo(&fs, dir, matches.value_of("undo")).map(|report| {
+                    print_warnings(&report);
+                    println!("Restored {} files", report.files());
+                })
+            }
+            Some(ref dir) => {
+                let finish = matches.value_of("recover") == Some("finish");
+                rename::recover(&fs, dir, finish).map(|reports| print_recovered(&reports, finish))
+            }
+            None => Err(Error::Batch("Cannot locate the journal directory".to_string())),
+        };
+
+        if let Err(e) = result {
+            println!("Error: {}", e);
+            std::process::exit(exit_code(&e));
+        }
+        return;
+    }
+
     let mode = matches.value_of("mode").unwrap_or("dir");
 
-    let dir = matches.value_of("directory");
-    let left = matches.value_of("left");
-    let right = matches.value_of("right");
+    // Paths may not be valid UTF-8
+    let dir = matches.value_of_os("directory").map(Path::new);
+    let left = matches.value_of_os("left").map(Path::new);
+    let right = matches.value_of_os("right").map(Path::new);
     let arg_depth = matches.value_of("depth");
 
     let editor = matches.value_of("editor").unwrap_or("vim");
     let exclude_dirs = matches.occurrences_of("exclude-dirs") > 0;
     let recursive = matches.occurrences_of("recursive") > 0;
+    let transactional = matches.occurrences_of("transactional") > 0;
+    let delete = matches.occurrences_of("delete") > 0;
+    let recursive_delete = matches.occurrences_of("recursive-delete") > 0;
+    let buffer = if matches.occurrences_of("numbered") > 0 {
+        BufferFormat::Numbered
+    } else if matches.occurrences_of("commands") > 0 {
+        BufferFormat::Commands
+    } else {
+        BufferFormat::Plain
+    };
+    let operation = if matches.occurrences_of("copy") > 0 {
+        Operation::Copy
+    } else if matches.occurrences_of("symlink") > 0 {
+        Operation::Symlink
+    } else if matches.occurrences_of("hardlink") > 0 {
+        Operation::Hardlink
+    } else {
+        Operation::Rename
+    };
+    let relative = matches.occurrences_of("relative") > 0;
+    let prune = matches.occurrences_of("prune") > 0;
+    let trash_dir = if matches.occurrences_of("unlink") > 0 {
+        None
+    } else {
+        rename::trash_dir()
+    };
+    let rename_dirs = matches.occurrences_of("rename-dirs") > 0;
+    let dry_run = matches.occurrences_of("dry-run") > 0;
+    let on_conflict = if matches.occurrences_of("overwrite") > 0 {
+        ConflictPolicy::Overwrite
+    } else {
+        matches
+            .value_of("on-conflict")
+            .and_then(ConflictPolicy::from_name)
+            .unwrap_or_default()
+    };
 
     let sort_type = matches.value_of("sort").unwrap_or("none");
     let descending = matches.occurrences_of("sort-desc") > 0;
@@ -117,12 +331,13 @@ fn main() {
         _ => Err(()),
     };
 
-    let sorting = sort_option.expect(&format!("Invalid sort option: {}", sort_type));
+    let sorting = sort_option.unwrap_or_else(|_| panic!("Invalid sort option: {}", sort_type));
 
-    let depth_option = match arg_depth {
-        Some(sdepth) => Some(sdepth.parse().expect(&format!("Invalid depth: {}", sdepth))),
-        None => None,
-    };
+    let depth_option = arg_depth.map(|sdepth| {
+        sdepth
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid depth: {}", sdepth))
+    });
 
     // If rename option is explicitly given, then always use given depth which could be None.
     // If depth_option is none, full recursive directory walk will be done for renaming files.
@@ -133,56 +348,175 @@ fn main() {
     };
 
     let renaming = match mode {
-        "left" => if left.is_some() {
-            Ok(app::RenameOp::from_left(
-                left.unwrap(),
-                editor,
-                false,
-                sorting,
-            ))
+        "left" => if let Some(left) = left {
+            Ok(app::RenameOp::from_left(left, editor, sorting))
         } else {
             Err("Left file arg is required for left mode".to_string())
         },
-        "compare" => if left.is_some() && right.is_some() {
-            Ok(app::RenameOp::from_compare(
-                left.unwrap(),
-                right.unwrap(),
-                false,
-            ))
+        "compare" => if let (Some(left), Some(right)) = (left, right) {
+            Ok(app::RenameOp::from_compare(left, right))
         } else {
             Err("Left file and right file args are required for compare mode".to_string())
         },
-        "dir" => if dir.is_some() {
-            Ok(app::RenameOp::from_dir(
-                dir.unwrap(),
-                editor,
-                depth,
-                exclude_dirs,
-                false,
-                sorting,
-            ))
+        "dir" => if let Some(dir) = dir {
+            Ok(app::RenameOp::from_dir(dir, editor, depth, exclude_dirs, sorting))
         } else {
             Err("Directory argument required for dir mode".to_string())
         },
-        "input" => Ok(app::RenameOp::from_stdin(editor, false, sorting)),
+        "input" => Ok(app::RenameOp::from_stdin(editor, sorting)),
         _ => Err(format!("Unexpected mode: {}", mode)),
     };
 
     match renaming {
         Ok(app) => {
-            let result = app.rename();
+            let result = app
+                .buffer(buffer)
+                .operation(operation)
+                .relative_links(relative)
+                .delete_removed(delete, recursive_delete)
+                .transactional(transactional)
+                .on_conflict(on_conflict)
+                .prune_empty(prune)
+                .rename_dirs(rename_dirs)
+                .trash(trash_dir)
+                .journal(journal_dir)
+                .dry_run(dry_run)
+                .rename();
 
             match result {
-                Ok(count) => println!("Renamed {} files", count),
-                Err(msg) => {
-                    println!("Error: {}", msg);
-                    std::process::exit(1);
+                Ok(report) => {
+                    print_report(&report, operation, dry_run);
+                    // A dry run of a transactional batch ends the way the real run would
+                    if !report.failed.is_empty() && dry_run && transactional {
+                        println!("Would roll back the batch, nothing would be changed");
+                        std::process::exit(EXIT_FAILED);
+                    }
+                    if !report.failed.is_empty() {
+                        std::process::exit(EXIT_PARTIAL);
+                    }
+                }
+                Err(e) => {
+                    println!("Error: {}", e);
+                    std::process::exit(exit_code(&e));
                 }
             }
         }
         Err(msg) => {
             println!("{}", msg);
-            std::process::exit(1);
+            std::process::exit(EXIT_INVALID);
+        }
+    }
+}
+
+fn print_warnings(report: &Report) {
+    for warning in &report.warnings {
+        println!("Warning - {}", warning);
+    }
+}
+
+// After a dry run, the report tells what a real run would do
+fn print_report(report: &Report, operation: Operation, dry_run: bool) {
+    print_warnings(report);
+    if let Some(ref id) = report.batch {
+        println!("Recorded batch {} in the journal", id);
+    }
+
+    // Files are counted by what was done to them, as a batch can mix operations
+    let mut counts: Vec<(&str, &str, usize)> = vec![];
+    for step in &report.renamed {
+        let (done, verb) = match *step {
+            Step::Copy(_) => ("Copied", "copy"),
+            Step::Symlink(_) => ("Symlinked", "symlink"),
+            Step::Hardlink(_) => ("Hard linked", "hard link"),
+            Step::Delete { .. } => ("Deleted", "delete"),
+            Step::Trash { .. } => ("Trashed", "trash"),
+            _ => ("Renamed", "rename"),
+        };
+        let files = match *step {
+            Step::Exchange(_) => 2,
+            _ => 1,
+        };
+        match counts.iter_mut().find(|&&mut (_, v, _)| v == verb) {
+            Some(count) => count.2 += files,
+            None => counts.push((done, verb, files)),
+        }
+    }
+    // A batch that changes nothing says so in the terms it was asked for
+    if counts.is_empty() {
+        counts.push(match operation {
+            Operation::Copy => ("Copied", "copy", 0),
+            Operation::Symlink | Operation::Hardlink => ("Linked", "link", 0),
+            _ => ("Renamed", "rename", 0),
+        });
+    }
+    for (done, verb, count) in counts {
+        if dry_run {
+            println!("Would {} {}", verb, plural(count, "file"));
+        } else {
+            println!("{} {}", done, plural(count, "file"));
+        }
+    }
+
+    if !report.skipped.is_empty() {
+        let skipped = if dry_run { "Would skip" } else { "Skipped" };
+        println!("{} {}:", skipped, plural(report.skipped.len(), "line"));
+        for (line, op, r) in &report.skipped {
+            println!("  line {}: {} {}", line, op, r);
+        }
+    }
+    if !report.failed.is_empty() {
+        let failed = if dry_run { "Would fail" } else { "Failed" };
+        println!("{} {}:", failed, plural(report.failed.len(), "step"));
+        for (_, e) in &report.failed {
+            println!("  {}", e);
         }
     }
 }
+
+fn plural(count: usize, noun: &str) -> String {
+    if count == 1 {
+        format!("1 {}", noun)
+    } else {
+        format!("{} {}s", count, noun)
+    }
+}
+
+// Each recovered batch lists the steps it ran or undid, and those that failed
+fn print_recovered(reports: &[Report], finish: bool) {
+    let mut count = 0;
+    for report in reports {
+        let id = report.batch.as_ref().map_or("", String::as_str);
+        print_warnings(report);
+        if finish {
+            for (_, e) in &report.failed {
+                println!("Warning - {}", e);
+            }
+            println!(
+                "Finished batch {}: {} of {} remaining steps",
+                id,
+                report.steps.len(),
+                report.steps.len() + report.failed.len()
+            );
+        } else {
+            println!("Batch {}: Rolled back {} renames", id, report.steps.len());
+            for step in &report.steps {
+                println!("  undone: {}", step);
+            }
+            for (_, e) in &report.failed {
+                println!("  {}", e);
+            }
+        }
+        count += report.steps.len();
+    }
+    println!("Recovered {} steps", count);
+}
+
+fn exit_code(e: &Error) -> i32 {
+    match *e {
+        Error::Line { .. } | Error::LineCount { .. } => EXIT_INVALID,
+        Error::Conflict { .. } => EXIT_CONFLICT,
+        Error::Batch(_) => EXIT_JOURNAL,
+        Error::Cancelled => EXIT_CANCELLED,
+        Error::Io { .. } | Error::RolledBack { .. } => EXIT_FAILED,
+    }
+}
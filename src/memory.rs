@@ -0,0 +1,968 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use filesystem::{FileInfo, FileKind, Filesystem, User};
+use libc;
+use trash;
+
+// Everything in memory is on the one device
+const DEVICE: u64 = 1;
+// Symlinks are followed this many times before giving up, as on Linux
+const MAX_LINKS: usize = 40;
+
+/// A tree of files held in memory, for working out and running plans without touching the disk.
+/// Paths are resolved by name, without following symlinks in their directories. Permissions are
+/// checked as they would be for the user set, root by default. Changes can also be made to fail,
+/// to see how a batch copes with a failure part way through.
+pub struct MemoryFs {
+    tree: Mutex<Tree>,
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    kind: FileKind,
+    mode: u32,
+    ino: u64,
+    uid: u32,
+    gid: u32,
+    // What a symlink points to
+    target: PathBuf,
+}
+
+struct Tree {
+    nodes: BTreeMap<PathBuf, Node>,
+    // File contents by inode, shared by hard links
+    contents: HashMap<u64, Vec<u8>>,
+    next_ino: u64,
+    cwd: PathBuf,
+    user: User,
+    // Paths that changes fail on, with the error they fail with
+    failing: Vec<(PathBuf, io::ErrorKind)>,
+    // The number of changes to let through before failing one
+    fail_after: Option<(usize, io::ErrorKind)>,
+}
+
+impl Default for MemoryFs {
+    fn default() -> Self {
+        MemoryFs::new()
+    }
+}
+
+impl MemoryFs {
+    /// An empty tree, with `/` as the current directory
+    pub fn new() -> Self {
+        let mut tree = Tree {
+            nodes: BTreeMap::new(),
+            contents: HashMap::new(),
+            next_ino: 1,
+            cwd: PathBuf::from("/"),
+            user: User::default(),
+            failing: vec![],
+            fail_after: None,
+        };
+        let root = tree.node(FileKind::Dir, 0o755);
+        tree.nodes.insert(PathBuf::from("/"), root);
+        MemoryFs {
+            tree: Mutex::new(tree),
+        }
+    }
+
+    /// A copy of the files at the given paths on another filesystem, to try out changes to them.
+    /// Each path is copied with everything under it, and its directories with everything directly
+    /// in them, as the user of the other filesystem sees them. File contents are left out.
+    pub fn snapshot(fs: &dyn Filesystem, paths: &[PathBuf]) -> io::Result<MemoryFs> {
+        let memory = MemoryFs::new();
+        memory.set_current_dir(fs.current_dir()?);
+
+        let mut tree = memory.tree();
+        let mut listed: HashSet<PathBuf> = HashSet::new();
+        for path in paths {
+            let path = tree.absolute(path);
+
+            // Directories are copied as what they are reached through, even when that is a
+            // symlink, as paths in memory are not resolved through symlinks
+            let dirs: Vec<PathBuf> = path.ancestors().skip(1).map(Path::to_path_buf).collect();
+            for dir in dirs.into_iter().rev() {
+                if let Ok(md) = fs.metadata(&dir) {
+                    tree.copy_node(fs, &dir, md);
+                }
+                if !listed.insert(dir.clone()) {
+                    continue;
+                }
+                for entry in fs.read_dir(&dir).unwrap_or_default() {
+                    if tree.nodes.contains_key(&entry) {
+                        continue;
+                    }
+                    if let Ok(md) = fs.symlink_metadata(&entry) {
+                        tree.copy_node(fs, &entry, md);
+                    }
+                }
+            }
+
+            let mut pending = vec![path];
+            while let Some(path) = pending.pop() {
+                let md = match fs.symlink_metadata(&path) {
+                    Ok(md) => md,
+                    Err(_) => continue,
+                };
+                tree.copy_node(fs, &path, md);
+                if md.is_dir() && listed.insert(path.clone()) {
+                    pending.extend(fs.read_dir(&path).unwrap_or_default());
+                }
+            }
+        }
+
+        tree.user = fs.user();
+        drop(tree);
+        Ok(memory)
+    }
+
+    pub fn set_current_dir<P: AsRef<Path>>(&self, path: P) -> &Self {
+        let mut tree = self.tree();
+        tree.cwd = tree.absolute(path.as_ref());
+        self
+    }
+
+    /// Adds a directory, along with any missing parents
+    pub fn add_dir<P: AsRef<Path>>(&self, path: P) -> &Self {
+        let mut tree = self.tree();
+        let path = tree.absolute(path.as_ref());
+        tree.create_dir_all(&path, false).unwrap();
+        self
+    }
+
+    pub fn add_file<P: AsRef<Path>, C: AsRef<[u8]>>(&self, path: P, contents: C) -> &Self {
+        let mut tree = self.tree();
+        let path = tree.absolute(path.as_ref());
+        tree.add_parent(&path);
+        let node = tree.node(FileKind::File, 0o644);
+        tree.contents.insert(node.ino, contents.as_ref().to_vec());
+        tree.nodes.insert(path, node);
+        self
+    }
+
+    pub fn add_symlink<P: AsRef<Path>, Q: AsRef<Path>>(&self, path: P, target: Q) -> &Self {
+        let mut tree = self.tree();
+        let path = tree.absolute(path.as_ref());
+        tree.add_parent(&path);
+        let mut node = tree.node(FileKind::Symlink, 0o777);
+        node.target = target.as_ref().to_path_buf();
+        tree.nodes.insert(path, node);
+        self
+    }
+
+    pub fn set_mode<P: AsRef<Path>>(&self, path: P, mode: u32) -> &Self {
+        let mut tree = self.tree();
+        let path = tree.absolute(path.as_ref());
+        if let Some(node) = tree.nodes.get_mut(&path) {
+            node.mode = mode;
+        }
+        self
+    }
+
+    pub fn set_owner<P: AsRef<Path>>(&self, path: P, uid: u32, gid: u32) -> &Self {
+        let mut tree = self.tree();
+        let path = tree.absolute(path.as_ref());
+        if let Some(node) = tree.nodes.get_mut(&path) {
+            node.uid = uid;
+            node.gid = gid;
+        }
+        self
+    }
+
+    /// Checks permissions as the given user from then on. Files made later belong to them.
+    pub fn set_user(&self, user: User) -> &Self {
+        self.tree().user = user;
+        self
+    }
+
+    /// Makes every change to the path fail with the given error
+    pub fn fail<P: AsRef<Path>>(&self, path: P, kind: io::ErrorKind) -> &Self {
+        let mut tree = self.tree();
+        let path = tree.absolute(path.as_ref());
+        tree.failing.push((path, kind));
+        self
+    }
+
+    /// Lets the given number of changes through, then fails the next one with the given error
+    pub fn fail_after(&self, changes: usize, kind: io::ErrorKind) -> &Self {
+        self.tree().fail_after = Some((changes, kind));
+        self
+    }
+
+    /// The contents of a file, following symlinks
+    pub fn read<P: AsRef<Path>>(&self, path: P) -> io::Result<Vec<u8>> {
+        let tree = self.tree();
+        let path = tree.resolve(&tree.absolute(path.as_ref()))?;
+        let node = tree.get(&path)?;
+        if node.kind == FileKind::Dir {
+            return Err(error(io::ErrorKind::IsADirectory, &path));
+        }
+        Ok(tree.contents.get(&node.ino).cloned().unwrap_or_default())
+    }
+
+    pub fn read_to_string<P: AsRef<Path>>(&self, path: P) -> io::Result<String> {
+        let contents = self.read(path)?;
+        String::from_utf8(contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Every path in the tree but the root, in order
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let tree = self.tree();
+        tree.nodes.keys().skip(1).cloned().collect()
+    }
+
+    // A change that panicked part way leaves the tree as it got, which is still a tree
+    fn tree(&self) -> MutexGuard<'_, Tree> {
+        self.tree.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Tree {
+    fn info(&self, node: &Node) -> FileInfo {
+        let size = match node.kind {
+            FileKind::File => self.contents.get(&node.ino).map_or(0, Vec::len),
+            FileKind::Symlink => node.target.as_os_str().len(),
+            FileKind::Dir => 0,
+        };
+        FileInfo {
+            kind: node.kind,
+            mode: node.mode,
+            dev: DEVICE,
+            ino: node.ino,
+            uid: node.uid,
+            gid: node.gid,
+            size: size as u64,
+        }
+    }
+
+    fn node(&mut self, kind: FileKind, mode: u32) -> Node {
+        self.next_ino += 1;
+        Node {
+            kind,
+            mode,
+            ino: self.next_ino,
+            uid: self.user.uid,
+            gid: self.user.groups.first().cloned().unwrap_or_default(),
+            target: PathBuf::new(),
+        }
+    }
+
+    fn copy_node(&mut self, fs: &dyn Filesystem, path: &Path, md: FileInfo) {
+        let mut node = self.node(md.kind, md.mode);
+        node.uid = md.uid;
+        node.gid = md.gid;
+        if md.is_symlink() {
+            node.target = fs.read_link(path).unwrap_or_default();
+        }
+        self.nodes.insert(path.to_path_buf(), node);
+    }
+
+    // Resolves the path against the current directory, and `.` and `..` by name
+    fn absolute(&self, path: &Path) -> PathBuf {
+        let mut absolute = PathBuf::new();
+        for component in self.cwd.join(path).components() {
+            match component {
+                Component::CurDir => (),
+                Component::ParentDir => {
+                    absolute.pop();
+                }
+                component => absolute.push(component),
+            }
+        }
+        absolute
+    }
+
+    fn get(&self, path: &Path) -> io::Result<&Node> {
+        self.nodes
+            .get(path)
+            .ok_or_else(|| error(io::ErrorKind::NotFound, path))
+    }
+
+    // Follows a symlink at the end of the path to the file it points to
+    fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        let mut path = path.to_path_buf();
+        for _ in 0..MAX_LINKS {
+            let node = self.get(&path)?;
+            if node.kind != FileKind::Symlink {
+                return Ok(path);
+            }
+            let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+            path = self.absolute(&dir.join(&node.target));
+        }
+        let message = format!("{}: too many levels of symbolic links", path.display());
+        Err(io::Error::other(message))
+    }
+
+    // A path and everything under it
+    fn subtree(&self, path: &Path) -> Vec<PathBuf> {
+        self.nodes
+            .range(path.to_path_buf()..)
+            .map(|(p, _)| p)
+            .take_while(|p| p.starts_with(path))
+            .cloned()
+            .collect()
+    }
+
+    fn is_empty_dir(&self, path: &Path) -> bool {
+        self.subtree(path).len() == 1
+    }
+
+    // New entries need a directory to go in
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        let parent = path.parent().unwrap_or(path);
+        let parent = self.resolve(parent)?;
+        match self.get(&parent)?.kind {
+            FileKind::Dir => Ok(()),
+            _ => Err(error(io::ErrorKind::NotADirectory, &parent)),
+        }
+    }
+
+    fn check_free(&self, path: &Path) -> io::Result<()> {
+        if self.nodes.contains_key(path) {
+            return Err(error(io::ErrorKind::AlreadyExists, path));
+        }
+        self.check_parent(path)
+    }
+
+    // Whether the user has the given permissions on a file, 4 to read, 2 to write and 1 to search
+    fn access(&self, path: &Path, wanted: u32) -> io::Result<()> {
+        let node = self.get(path)?;
+        let shift = if node.uid == self.user.uid {
+            6
+        } else if self.user.groups.contains(&node.gid) {
+            3
+        } else {
+            0
+        };
+        if self.user.is_root() || (node.mode >> shift) & wanted == wanted {
+            Ok(())
+        } else {
+            Err(error(io::ErrorKind::PermissionDenied, path))
+        }
+    }
+
+    // Adding an entry to a directory takes write and search permission on it
+    fn may_add(&self, path: &Path) -> io::Result<()> {
+        let parent = self.resolve(path.parent().unwrap_or(path))?;
+        self.access(&parent, 0o3)
+    }
+
+    // So does removing one, and in a sticky directory only the owner of the entry or of the
+    // directory may remove it
+    fn may_remove(&self, path: &Path) -> io::Result<()> {
+        let parent = self.resolve(path.parent().unwrap_or(path))?;
+        self.access(&parent, 0o3)?;
+        let (dir, node) = (self.get(&parent)?, self.get(path)?);
+        let uid = self.user.uid;
+        if dir.mode & 0o1000 != 0 && !self.user.is_root() && uid != dir.uid && uid != node.uid {
+            return Err(error(io::ErrorKind::PermissionDenied, path));
+        }
+        Ok(())
+    }
+
+    // Moving a directory to another parent rewrites its `..` entry, which takes write permission
+    // on the directory itself
+    fn may_move(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.may_remove(from)?;
+        self.may_add(to)?;
+        if self.get(from)?.kind == FileKind::Dir && from.parent() != to.parent() {
+            self.access(from, 0o2)?;
+        }
+        Ok(())
+    }
+
+    // Counts a change to the given paths, failing it if asked to
+    fn check(&mut self, paths: &[&Path]) -> io::Result<()> {
+        for path in paths {
+            if let Some(&(_, kind)) = self.failing.iter().find(|(p, _)| p == path) {
+                return Err(error(kind, path));
+            }
+        }
+        match self.fail_after {
+            Some((0, kind)) => {
+                self.fail_after = None;
+                Err(error(kind, paths[0]))
+            }
+            Some((n, kind)) => {
+                self.fail_after = Some((n - 1, kind));
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn add_parent(&mut self, path: &Path) {
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent, false).unwrap();
+        }
+    }
+
+    // Makes the directories missing on the path, checking the permissions to do so if asked to
+    fn create_dir_all(&mut self, path: &Path, checked: bool) -> io::Result<()> {
+        let mut dir = PathBuf::new();
+        for component in path.components() {
+            dir.push(component);
+            let existing = self.nodes.get(&dir).map(|_| self.resolve(&dir));
+            match existing {
+                Some(resolved) => {
+                    if self.get(&resolved?)?.kind != FileKind::Dir {
+                        return Err(error(io::ErrorKind::NotADirectory, &dir));
+                    }
+                }
+                None => {
+                    if checked {
+                        self.may_add(&dir)?;
+                    }
+                    let node = self.node(FileKind::Dir, 0o755);
+                    self.nodes.insert(dir.clone(), node);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn move_tree(&mut self, from: &Path, to: &Path) {
+        for path in self.subtree(from) {
+            let node = self.nodes.remove(&path).unwrap();
+            let rest = path.strip_prefix(from).unwrap();
+            self.nodes.insert(join(to, rest), node);
+        }
+    }
+
+    fn remove_tree(&mut self, path: &Path) {
+        for path in self.subtree(path) {
+            self.nodes.remove(&path);
+        }
+    }
+
+    // Renames as rename(2) does, replacing a file with a file and a directory with an empty one
+    fn rename(&mut self, from: &Path, to: &Path, replace: bool) -> io::Result<()> {
+        let kind = self.get(from)?.kind;
+        self.check_parent(to)?;
+        if from == to {
+            return Ok(());
+        }
+        if to.starts_with(from) {
+            return Err(error(io::ErrorKind::InvalidInput, to));
+        }
+        self.may_move(from, to)?;
+
+        if let Some(existing) = self.nodes.get(to).map(|node| node.kind) {
+            if !replace {
+                return Err(error(io::ErrorKind::AlreadyExists, to));
+            }
+            self.may_remove(to)?;
+            match (kind, existing) {
+                (FileKind::Dir, FileKind::Dir) if !self.is_empty_dir(to) => {
+                    return Err(error(io::ErrorKind::DirectoryNotEmpty, to));
+                }
+                (FileKind::Dir, FileKind::Dir) => (),
+                (_, FileKind::Dir) => return Err(error(io::ErrorKind::IsADirectory, to)),
+                (FileKind::Dir, _) => return Err(error(io::ErrorKind::NotADirectory, to)),
+                _ => (),
+            }
+            self.remove_tree(to);
+        }
+
+        self.move_tree(from, to);
+        Ok(())
+    }
+}
+
+impl Filesystem for MemoryFs {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        let tree = self.tree();
+        tree.get(&tree.absolute(path)).map(|node| tree.info(node))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        let tree = self.tree();
+        let path = tree.resolve(&tree.absolute(path))?;
+        tree.get(&path).map(|node| tree.info(node))
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        let tree = self.tree();
+        let absolute = tree.absolute(path);
+        let node = tree.get(&absolute)?;
+        if node.kind != FileKind::Symlink {
+            return Err(error(io::ErrorKind::InvalidInput, &absolute));
+        }
+        Ok(node.target.clone())
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let tree = self.tree();
+        let dir = tree.resolve(&tree.absolute(path))?;
+        if tree.get(&dir)?.kind != FileKind::Dir {
+            return Err(error(io::ErrorKind::NotADirectory, &dir));
+        }
+        let depth = dir.components().count() + 1;
+        Ok(tree
+            .subtree(&dir)
+            .iter()
+            .filter(|p| p.components().count() == depth)
+            .filter_map(|p| p.file_name())
+            .map(|name| path.join(name))
+            .collect())
+    }
+
+    fn current_dir(&self) -> io::Result<PathBuf> {
+        Ok(self.tree().cwd.clone())
+    }
+
+    fn user(&self) -> User {
+        self.tree().user.clone()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut tree = self.tree();
+        let path = tree.absolute(path);
+        tree.check(&[&path])?;
+        tree.create_dir_all(&path, true)
+    }
+
+    fn rename(&self, from: &Path, to: &Path, replace: bool) -> io::Result<()> {
+        let mut tree = self.tree();
+        let (from, to) = (tree.absolute(from), tree.absolute(to));
+        tree.check(&[&from, &to])?;
+        tree.rename(&from, &to, replace)
+    }
+
+    fn exchange(&self, a: &Path, b: &Path) -> io::Result<()> {
+        let mut tree = self.tree();
+        let (a, b) = (tree.absolute(a), tree.absolute(b));
+        tree.check(&[&a, &b])?;
+        tree.get(&a)?;
+        tree.get(&b)?;
+        if a.starts_with(&b) || b.starts_with(&a) {
+            return Err(error(io::ErrorKind::InvalidInput, &b));
+        }
+        tree.may_move(&a, &b)?;
+        tree.may_move(&b, &a)?;
+
+        let parked = a.with_file_name("\0exchange");
+        tree.move_tree(&a, &parked);
+        tree.move_tree(&b, &a);
+        tree.move_tree(&parked, &b);
+        Ok(())
+    }
+
+    // Copies are new files with contents of their own, belonging to the user. Symlinks are
+    // copied as links.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut tree = self.tree();
+        let (from, to) = (tree.absolute(from), tree.absolute(to));
+        tree.check(&[&from, &to])?;
+        tree.get(&from)?;
+        tree.check_free(&to)?;
+        if to.starts_with(&from) {
+            return Err(error(io::ErrorKind::InvalidInput, &to));
+        }
+        tree.may_add(&to)?;
+        for path in tree.subtree(&from) {
+            match tree.nodes[&path].kind {
+                FileKind::Dir => tree.access(&path, 0o5)?,
+                FileKind::File => tree.access(&path, 0o4)?,
+                FileKind::Symlink => (),
+            }
+        }
+
+        for path in tree.subtree(&from) {
+            let original = tree.nodes[&path].clone();
+            let mut copy = tree.node(original.kind, original.mode);
+            copy.target = original.target;
+            if let Some(contents) = tree.contents.get(&original.ino).cloned() {
+                tree.contents.insert(copy.ino, contents);
+            }
+            let rest = path.strip_prefix(&from).unwrap().to_path_buf();
+            tree.nodes.insert(join(&to, &rest), copy);
+        }
+        Ok(())
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        let mut tree = self.tree();
+        let link = tree.absolute(link);
+        tree.check(&[&link])?;
+        tree.check_free(&link)?;
+        tree.may_add(&link)?;
+        let mut node = tree.node(FileKind::Symlink, 0o777);
+        node.target = target.to_path_buf();
+        tree.nodes.insert(link, node);
+        Ok(())
+    }
+
+    // Both names share the one file, contents included
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut tree = self.tree();
+        let (from, to) = (tree.absolute(from), tree.absolute(to));
+        tree.check(&[&from, &to])?;
+        let node = tree.get(&from)?.clone();
+        if node.kind == FileKind::Dir {
+            return Err(error(io::ErrorKind::PermissionDenied, &from));
+        }
+        tree.check_free(&to)?;
+        tree.may_add(&to)?;
+        tree.nodes.insert(to, node);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut tree = self.tree();
+        let path = tree.absolute(path);
+        tree.check(&[&path])?;
+        if tree.get(&path)?.kind == FileKind::Dir {
+            return Err(error(io::ErrorKind::IsADirectory, &path));
+        }
+        tree.may_remove(&path)?;
+        tree.nodes.remove(&path);
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let mut tree = self.tree();
+        let path = tree.absolute(path);
+        tree.check(&[&path])?;
+        if tree.get(&path)?.kind != FileKind::Dir {
+            return Err(error(io::ErrorKind::NotADirectory, &path));
+        }
+        if !tree.is_empty_dir(&path) {
+            return Err(error(io::ErrorKind::DirectoryNotEmpty, &path));
+        }
+        tree.may_remove(&path)?;
+        tree.nodes.remove(&path);
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut tree = self.tree();
+        let path = tree.absolute(path);
+        tree.check(&[&path])?;
+        tree.may_remove(&path)?;
+        // Emptying each directory takes reading, writing and searching it
+        for path in tree.subtree(&path) {
+            if tree.nodes[&path].kind == FileKind::Dir {
+                tree.access(&path, 0o7)?;
+            }
+        }
+        tree.remove_tree(&path);
+        Ok(())
+    }
+
+    // Keeps to the layout of a freedesktop.org trash, info file included
+    fn trash(&self, path: &Path, trashed: &Path) -> io::Result<()> {
+        let mut tree = self.tree();
+        let (path, trashed) = (tree.absolute(path), tree.absolute(trashed));
+        tree.check(&[&path, &trashed])?;
+        tree.get(&path)?;
+
+        let info = trash::info_path(&trashed);
+        for dir in [trashed.parent(), info.parent()].iter().flatten() {
+            tree.create_dir_all(dir, true)?;
+        }
+        tree.check_free(&info)?;
+        tree.check_free(&trashed)?;
+        tree.may_add(&info)?;
+        tree.may_move(&path, &trashed)?;
+
+        let node = tree.node(FileKind::File, 0o600);
+        tree.contents
+            .insert(node.ino, trash::info_for(&path).into_bytes());
+        tree.nodes.insert(info, node);
+        tree.move_tree(&path, &trashed);
+        Ok(())
+    }
+
+    fn restore(&self, trashed: &Path, path: &Path) -> io::Result<()> {
+        let mut tree = self.tree();
+        let (trashed, path) = (tree.absolute(trashed), tree.absolute(path));
+        tree.check(&[&trashed, &path])?;
+        tree.rename(&trashed, &path, true)?;
+        tree.nodes.remove(&trash::info_path(&trashed));
+        Ok(())
+    }
+}
+
+
+fn join(dir: &Path, rest: &Path) -> PathBuf {
+    if rest.as_os_str().is_empty() {
+        dir.to_path_buf()
+    } else {
+        dir.join(rest)
+    }
+}
+
+// Errors read as the system's would, naming the path they are about
+fn error(kind: io::ErrorKind, path: &Path) -> io::Error {
+    io::Error::new(kind, format!("{}: {}", path.display(), cause(kind)))
+}
+
+#[cfg(unix)]
+fn cause(kind: io::ErrorKind) -> io::Error {
+    let errno = match kind {
+        io::ErrorKind::NotFound => Some(libc::ENOENT),
+        io::ErrorKind::AlreadyExists => Some(libc::EEXIST),
+        io::ErrorKind::PermissionDenied => Some(libc::EACCES),
+        io::ErrorKind::IsADirectory => Some(libc::EISDIR),
+        io::ErrorKind::NotADirectory => Some(libc::ENOTDIR),
+        io::ErrorKind::DirectoryNotEmpty => Some(libc::ENOTEMPTY),
+        io::ErrorKind::InvalidInput => Some(libc::EINVAL),
+        _ => None,
+    };
+    match errno {
+        Some(errno) => io::Error::from_raw_os_error(errno),
+        None => io::Error::from(kind),
+    }
+}
+
+#[cfg(not(unix))]
+fn cause(kind: io::ErrorKind) -> io::Error {
+    io::Error::from(kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryFs;
+    use error::Error;
+    use filesystem::{Filesystem, RealFs, User};
+    use plan::RenamePlan;
+    use rename::{undo, RenameOptions, TEMP_PREFIX};
+    use std::env;
+    use std::fs;
+    use std::io::{self, ErrorKind};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn memory_fs_test() {
+        let fs = MemoryFs::new();
+        fs.add_file("/d/a", "a")
+            .add_file("/d/b", "b")
+            .add_file("/d/sub/c", "c")
+            .add_symlink("/d/link", "a")
+            .set_current_dir("/d");
+
+        assert_eq!(fs.read("link").unwrap(), b"a");
+        assert!(fs.symlink_metadata(Path::new("link")).unwrap().is_symlink());
+        assert!(!fs.metadata(Path::new("link")).unwrap().is_symlink());
+        assert_eq!(
+            fs.read_dir(Path::new("sub")).unwrap(),
+            vec![PathBuf::from("sub/c")]
+        );
+
+        // Renames only replace when asked to, and never a directory with a file
+        let (a, b) = (Path::new("a"), Path::new("b"));
+        assert_eq!(
+            fs.rename(a, b, false).unwrap_err().kind(),
+            ErrorKind::AlreadyExists
+        );
+        assert_eq!(
+            fs.rename(a, Path::new("sub"), true).unwrap_err().kind(),
+            ErrorKind::IsADirectory
+        );
+        assert!(fs
+            .rename(Path::new("sub"), Path::new("sub/deeper"), false)
+            .is_err());
+
+        fs.exchange(a, b).unwrap();
+        assert_eq!(fs.read("a").unwrap(), b"b");
+        fs.rename(Path::new("sub"), Path::new("moved"), false)
+            .unwrap();
+        assert_eq!(fs.read("moved/c").unwrap(), b"c");
+
+        // Copies get files of their own, hard links share theirs
+        fs.copy(Path::new("moved"), Path::new("copied")).unwrap();
+        fs.hard_link(a, Path::new("hard")).unwrap();
+        let info = |p: &str| fs.symlink_metadata(Path::new(p)).unwrap().ino;
+        assert_ne!(info("moved/c"), info("copied/c"));
+        assert_eq!(info("a"), info("hard"));
+
+        assert_eq!(
+            fs.remove_dir(Path::new("moved")).unwrap_err().kind(),
+            ErrorKind::DirectoryNotEmpty
+        );
+        fs.remove_dir_all(Path::new("moved")).unwrap();
+        fs.remove_file(Path::new("hard")).unwrap();
+        assert_eq!(
+            fs.paths(),
+            paths(&["/d", "/d/a", "/d/b", "/d/copied", "/d/copied/c", "/d/link"])
+        );
+
+        // Injected failures
+        fs.fail("/d/b", ErrorKind::PermissionDenied);
+        assert_eq!(
+            fs.remove_file(b).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        fs.fail_after(1, ErrorKind::Other);
+        fs.remove_file(Path::new("link")).unwrap();
+        assert!(fs.remove_file(a).is_err());
+        fs.remove_file(a).unwrap();
+    }
+
+    #[test]
+    fn permissions_test() {
+        let fs = MemoryFs::new();
+        fs.add_file("/home/a", "a")
+            .add_file("/locked/b", "b")
+            .add_file("/tmp/c", "c")
+            .set_mode("/locked", 0o555)
+            .set_mode("/tmp", 0o1777)
+            .set_owner("/home", 1000, 1000)
+            .set_owner("/home/a", 1000, 1000);
+
+        // Root may change anything
+        fs.rename(Path::new("/locked/b"), Path::new("/locked/d"), false)
+            .unwrap();
+
+        fs.set_user(User {
+            uid: 1000,
+            groups: vec![1000],
+        });
+        fs.rename(Path::new("/home/a"), Path::new("/home/e"), false)
+            .unwrap();
+        let denied = |result: io::Result<()>| result.unwrap_err().kind();
+        assert_eq!(
+            denied(fs.rename(Path::new("/locked/d"), Path::new("/locked/b"), false)),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            denied(fs.rename(Path::new("/home/e"), Path::new("/locked/e"), false)),
+            ErrorKind::PermissionDenied
+        );
+        // Files of others in a sticky directory can only be read
+        assert_eq!(
+            denied(fs.remove_file(Path::new("/tmp/c"))),
+            ErrorKind::PermissionDenied
+        );
+        fs.copy(Path::new("/tmp/c"), Path::new("/tmp/f")).unwrap();
+        fs.remove_file(Path::new("/tmp/f")).unwrap();
+    }
+
+    #[test]
+    fn snapshot_test() {
+        let dir = env::temp_dir().join("snapshot_test");
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(dir.join("sub/deeper")).unwrap();
+        fs::write(dir.join("a"), "a").unwrap();
+        fs::write(dir.join("sub/deeper/b"), "b").unwrap();
+        fs::create_dir(dir.join("other")).unwrap();
+        fs::write(dir.join("other/c"), "c").unwrap();
+
+        let snapshot = MemoryFs::snapshot(&RealFs::new(), &[dir.join("sub")]).unwrap();
+        // The path comes with everything under it, its directory with what is directly in it
+        assert!(snapshot.exists(&dir.join("sub/deeper/b")));
+        assert!(snapshot.exists(&dir.join("a")));
+        assert!(snapshot.metadata(&dir.join("other")).unwrap().is_dir());
+        assert!(!snapshot.exists(&dir.join("other/c")));
+        assert_eq!(snapshot.read(dir.join("a")).unwrap(), b"");
+
+        // Changes to the snapshot stay in memory
+        snapshot
+            .rename(&dir.join("sub"), &dir.join("moved"), false)
+            .unwrap();
+        assert!(snapshot.exists(&dir.join("moved/deeper/b")));
+        assert!(dir.join("sub").exists());
+    }
+
+    #[test]
+    fn memory_plan_test() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file("/d/a", "a")
+            .add_file("/d/b", "b")
+            .add_file("/d/c", "c");
+
+        let rotation = |options: RenameOptions| {
+            let mut plan = RenamePlan::on(fs.clone(), options);
+            plan.rename("/d/a", "/d/b")
+                .rename("/d/b", "/d/c")
+                .rename("/d/c", "/d/a");
+            plan
+        };
+        let contents = || -> Vec<String> {
+            ["/d/a", "/d/b", "/d/c"]
+                .iter()
+                .map(|p| fs.read_to_string(p).unwrap())
+                .collect()
+        };
+
+        let report = rotation(RenameOptions::default()).execute().unwrap();
+        assert_eq!(report.files(), 3);
+        assert_eq!(report.steps.len(), 4);
+        assert_eq!(contents(), vec!["c", "a", "b"]);
+
+        // A failure part way through a transactional batch undoes the steps before it
+        fs.fail_after(2, ErrorKind::PermissionDenied);
+        let options = RenameOptions {
+            transactional: true,
+            ..RenameOptions::default()
+        };
+        match rotation(options).execute() {
+            Err(Error::RolledBack { ref rollback, .. }) => {
+                assert_eq!(rollback.undone.len(), 2);
+                assert!(rollback.failed.is_empty());
+            }
+            result => panic!("Expected a rolled back batch, got {:?}", result),
+        }
+        assert_eq!(contents(), vec!["c", "a", "b"]);
+        assert_eq!(fs.paths(), paths(&["/d", "/d/a", "/d/b", "/d/c"]));
+
+        // Otherwise the failed steps are reported and the rest run on
+        fs.fail_after(2, ErrorKind::PermissionDenied);
+        let report = rotation(RenameOptions::default()).execute().unwrap();
+        assert_eq!(report.renamed.len(), 1);
+        assert_eq!(report.failed.len(), 2);
+        assert_eq!(fs.read_to_string("/d/a").unwrap(), "b");
+        assert_eq!(fs.read_to_string("/d/b").unwrap(), "a");
+        // The first file is left at its temporary name
+        let left: Vec<PathBuf> = fs.paths().into_iter().skip(3).collect();
+        assert_eq!(left.len(), 1);
+        let name = left[0].file_name().unwrap().to_string_lossy().into_owned();
+        assert!(name.starts_with(TEMP_PREFIX));
+    }
+
+    #[test]
+    fn equivalent_paths_test() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.add_file("/d/a", "a").add_file("/d/b", "b").set_current_dir("/d");
+
+        // `./b` and `b` are the same file, so the two lines swap them
+        let mut plan = RenamePlan::on(fs.clone(), RenameOptions::default());
+        plan.rename("a", "./b").rename("b", "a");
+        assert_eq!(plan.execute().unwrap().files(), 2);
+        assert_eq!(fs.read_to_string("/d/a").unwrap(), "b");
+        assert_eq!(fs.read_to_string("/d/b").unwrap(), "a");
+    }
+
+    #[test]
+    fn memory_journal_test() {
+        let journal_dir = env::temp_dir().join("memory_journal_test");
+        if journal_dir.exists() {
+            fs::remove_dir_all(&journal_dir).unwrap();
+        }
+        let memory = Arc::new(MemoryFs::new());
+        memory.add_file("/d/a", "a").set_current_dir("/d");
+
+        // Paths are recorded under the current directory of the filesystem, not of the process
+        let options = RenameOptions {
+            journal: Some(journal_dir.clone()),
+            ..RenameOptions::default()
+        };
+        let mut plan = RenamePlan::on(memory.clone(), options);
+        plan.rename("a", "b");
+        assert!(plan.execute().unwrap().batch.is_some());
+
+        assert_eq!(undo(&*memory, &journal_dir, None).unwrap().files(), 1);
+        assert_eq!(memory.paths(), paths(&["/d", "/d/a"]));
+    }
+}
@@ -0,0 +1,279 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use error::Error;
+use filesystem::{Filesystem, RealFs};
+use rename::{self, Operation, Planned, RenameOptions, Report, Step};
+
+/// A batch of operations to run on files, one line per file, built up before anything is touched.
+/// Validating the plan works out the steps that carry it out, which can then be previewed and
+/// executed. Plans can be sent to another thread to run there.
+#[derive(Clone)]
+pub struct RenamePlan {
+    froms: Vec<PathBuf>,
+    tos: Vec<(Operation, PathBuf)>,
+    lines: Vec<usize>,
+    options: RenameOptions,
+    fs: Arc<dyn Filesystem>,
+}
+
+impl RenamePlan {
+    /// An empty plan run against the real filesystem
+    pub fn new(options: RenameOptions) -> Self {
+        RenamePlan::on(Arc::new(RealFs::new()), options)
+    }
+
+    /// A plan run against the given filesystem rather than the real one, such as a `MemoryFs`
+    pub fn on(fs: Arc<dyn Filesystem>, options: RenameOptions) -> Self {
+        RenamePlan {
+            froms: vec![],
+            tos: vec![],
+            lines: vec![],
+            options,
+            fs,
+        }
+    }
+
+    /// Adds a line running `op` on `from`, which gets the name `to`. Deleted and kept files need
+    /// no name.
+    pub fn add<P, Q>(&mut self, from: P, op: Operation, to: Q) -> &mut Self
+    where
+        P: Into<PathBuf>,
+        Q: Into<PathBuf>,
+    {
+        let line = self.len() + 1;
+        self.add_line(line, from, op, to)
+    }
+
+    /// Adds a line as `add` does, numbering it `line` rather than by the order lines are added,
+    /// such as a line of an edited buffer. Conflicts and skipped lines are reported by these
+    /// numbers.
+    pub fn add_line<P, Q>(&mut self, line: usize, from: P, op: Operation, to: Q) -> &mut Self
+    where
+        P: Into<PathBuf>,
+        Q: Into<PathBuf>,
+    {
+        let from = from.into();
+        // A kept file keeps its name, whatever it is given
+        let to = match op {
+            Operation::Keep => from.clone(),
+            _ => to.into(),
+        };
+        self.froms.push(from);
+        self.tos.push((op, to));
+        self.lines.push(line);
+        self
+    }
+
+    /// Adds a line renaming `from` to `to`
+    pub fn rename<P, Q>(&mut self, from: P, to: Q) -> &mut Self
+    where
+        P: Into<PathBuf>,
+        Q: Into<PathBuf>,
+    {
+        self.add(from, Operation::Rename, to)
+    }
+
+    /// Adds a line deleting `path`, moving it to the trash if the options give one
+    pub fn delete<P: Into<PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.add(path, Operation::Delete, PathBuf::new())
+    }
+
+    /// The number of lines added
+    pub fn len(&self) -> usize {
+        self.froms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.froms.is_empty()
+    }
+
+    pub fn options(&self) -> &RenameOptions {
+        &self.options
+    }
+
+    /// Checks the lines against each other and the files on disk, settling conflicts by the policy
+    /// of the options, and works out the steps to run. Nothing is changed.
+    pub fn validate(&self) -> Result<ValidPlan, Error> {
+        Ok(ValidPlan {
+            planned: rename::plan(&*self.fs, &self.froms, &self.tos, &self.lines, &self.options)?,
+            options: self.options.clone(),
+            fs: self.fs.clone(),
+        })
+    }
+
+    /// The steps the plan would run, described one per line
+    pub fn preview(&self) -> Result<Vec<String>, Error> {
+        self.validate().map(|plan| plan.preview())
+    }
+
+    /// Validates the plan and runs its steps
+    pub fn execute(&self) -> Result<Report, Error> {
+        self.validate()?.execute()
+    }
+}
+
+/// A plan whose steps have been worked out, ready to run
+pub struct ValidPlan {
+    planned: Planned,
+    options: RenameOptions,
+    fs: Arc<dyn Filesystem>,
+}
+
+impl ValidPlan {
+    /// Every step in the order it runs, including temporary moves and the removal of replaced files
+    pub fn steps(&self) -> &[Step] {
+        &self.planned.steps
+    }
+
+    /// Lines that the conflict policy leaves as they are, with the operation they asked for
+    pub fn skipped(&self) -> &[(usize, Operation, rename::Rename)] {
+        &self.planned.skipped
+    }
+
+    /// The steps described one per line, as `rename a -> b`
+    pub fn preview(&self) -> Vec<String> {
+        self.steps().iter().map(|step| step.to_string()).collect()
+    }
+
+    /// Runs the steps against an in-memory copy of the files they touch, reporting what a real run
+    /// would do and which steps would fail. Nothing is changed. A transactional plan reports the
+    /// step that would stop it as failed and nothing as done, where `execute` returns an error.
+    pub fn simulate(&self) -> Result<Report, Error> {
+        rename::simulate(&*self.fs, &self.planned, &self.options)
+    }
+
+    /// Runs the steps. A failed step is reported and the rest run on, unless the options make the
+    /// plan transactional, in which case the steps done are undone and an error returned.
+    pub fn execute(self) -> Result<Report, Error> {
+        rename::run(&*self.fs, &self.planned, &self.options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RenamePlan, ValidPlan};
+    use rename::{Operation, RenameOptions};
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::thread;
+
+    #[test]
+    fn plan_test() {
+        let dir = env::temp_dir().join("plan_test");
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("a"), "a").unwrap();
+        fs::write(dir.join("b"), "b").unwrap();
+        fs::write(dir.join("c"), "c").unwrap();
+
+        let mut plan = RenamePlan::new(RenameOptions::default());
+        plan.rename(dir.join("a"), dir.join("b"))
+            .rename(dir.join("b"), dir.join("a"))
+            .add(dir.join("c"), Operation::Copy, dir.join("d"));
+        assert_eq!(plan.len(), 3);
+
+        // Validating and previewing leave the files alone
+        let plan = plan.validate().unwrap();
+        assert_eq!(
+            plan.preview(),
+            vec![
+                format!("swap {} <-> {}", dir.join("a").display(), dir.join("b").display()),
+                format!("copy {} -> {}", dir.join("c").display(), dir.join("d").display()),
+            ]
+        );
+        assert_eq!(fs::read_to_string(dir.join("a")).unwrap(), "a");
+        assert!(!dir.join("d").exists());
+
+        let report = plan.execute().unwrap();
+        assert_eq!(report.files(), 3);
+        assert_eq!(report.steps.len(), 2);
+        assert!(report.skipped.is_empty() && report.failed.is_empty());
+        assert_eq!(report.batch, None);
+        assert_eq!(fs::read_to_string(dir.join("a")).unwrap(), "b");
+        assert_eq!(fs::read_to_string(dir.join("d")).unwrap(), "c");
+
+        // Conflicts are found without running anything
+        let mut plan = RenamePlan::new(RenameOptions::default());
+        plan.rename(dir.join("a"), dir.join("e"))
+            .rename(dir.join("b"), dir.join("e"));
+        assert!(plan.validate().is_err());
+        assert!(dir.join("a").exists());
+
+        // Kept files need no name
+        let mut plan = RenamePlan::new(RenameOptions::default());
+        plan.add(dir.join("a"), Operation::Keep, PathBuf::new())
+            .add(dir.join("b"), Operation::Keep, PathBuf::new());
+        assert!(plan.validate().unwrap().steps().is_empty());
+    }
+
+    #[test]
+    fn simulate_test() {
+        let dir = env::temp_dir().join("simulate_test");
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("a"), "a").unwrap();
+        fs::write(dir.join("b"), "b").unwrap();
+
+        let mut plan = RenamePlan::new(RenameOptions::default());
+        plan.rename(dir.join("a"), dir.join("b"))
+            .rename(dir.join("b"), dir.join("a"))
+            .rename(dir.join("missing"), dir.join("c"));
+        let plan = plan.validate().unwrap();
+
+        // Every step runs in memory, and the missing source is found
+        let report = plan.simulate().unwrap();
+        assert_eq!(report.files(), 2);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0.path(), dir.join("missing").as_path());
+        assert_eq!(report.batch, None);
+        assert_eq!(fs::read_to_string(dir.join("a")).unwrap(), "a");
+
+        // A transactional run would stop at the failure and undo the rest
+        let options = RenameOptions {
+            transactional: true,
+            ..RenameOptions::default()
+        };
+        let mut transactional = RenamePlan::new(options);
+        transactional
+            .rename(dir.join("missing"), dir.join("c"))
+            .rename(dir.join("a"), dir.join("d"))
+            .rename(dir.join("gone"), dir.join("e"));
+        let report = transactional.validate().unwrap().simulate().unwrap();
+        assert_eq!(report.files(), 0);
+        assert_eq!(report.failed.len(), 1);
+        assert!(dir.join("a").exists());
+
+        // The real run does what the simulation said
+        let report = plan.execute().unwrap();
+        assert_eq!(report.files(), 2);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(fs::read_to_string(dir.join("a")).unwrap(), "b");
+    }
+
+    #[test]
+    fn send_test() {
+        fn assert_send<T: Send>() {}
+        assert_send::<RenamePlan>();
+        assert_send::<ValidPlan>();
+
+        let dir = env::temp_dir().join("send_test");
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("a"), "a").unwrap();
+
+        let mut plan = RenamePlan::new(RenameOptions::default());
+        plan.rename(dir.join("a"), dir.join("b"));
+        let plan = plan.validate().unwrap();
+        let report = thread::spawn(move || plan.execute().unwrap()).join().unwrap();
+        assert_eq!(report.files(), 1);
+        assert_eq!(fs::read_to_string(dir.join("b")).unwrap(), "a");
+    }
+}
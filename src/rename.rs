@@ -1,148 +1,1397 @@
-use std::collections::hash_map::DefaultHasher;
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
-use std::fs;
-use std::hash::{Hash, Hasher};
-use std::path::PathBuf;
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use conflict::{self, ConflictPolicy, Prompt};
+use error::Error;
+use filesystem::{Filesystem, RealFs};
+use journal;
+use memory::MemoryFs;
+use trash;
+
+/// Files parked under a temporary name while a cycle of renames runs start with this prefix, so
+/// any left behind by an interrupted run can be recognised.
+pub const TEMP_PREFIX: &str = "rename-tmp-";
+
+#[derive(Clone, Default)]
+pub struct RenameOptions {
+    /// Stop at the first failed rename and undo everything done before it
+    pub transactional: bool,
+    /// Directory to record executed batches in, so they can be undone later
+    pub journal: Option<PathBuf>,
+    /// How to settle targets claimed by several renames or by files outside the batch
+    pub on_conflict: ConflictPolicy,
+    /// Asked about each conflict when the policy is to prompt
+    pub prompt: Option<Prompt>,
+    /// Remove directories that moves have left empty
+    pub prune_empty: bool,
+    /// Rename a parent directory when a line changes it, instead of moving just that file
+    pub rename_dirs: bool,
+    /// Delete directories along with their contents, rather than only empty ones
+    pub recursive_delete: bool,
+    /// Home trash that deleted and overwritten files are moved to, they are unlinked without one
+    pub trash: Option<PathBuf>,
+    /// Symlinks point to their file by a path relative to the link, rather than an absolute one
+    pub relative_links: bool,
+}
+
+/// What a line of the plan does with its file
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Operation {
+    #[default]
+    Rename,
+    Copy,
+    Symlink,
+    Hardlink,
+    Delete,
+    /// Leaves the file as it is
+    Keep,
+}
+
+impl Operation {
+    /// Whether the file is gone from its original name once the operation has run
+    pub fn vacates(self) -> bool {
+        self == Operation::Rename || self == Operation::Delete
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Operation::Rename => "rename",
+            Operation::Copy => "copy",
+            Operation::Symlink => "symlink",
+            Operation::Hardlink => "hardlink",
+            Operation::Delete => "delete",
+            Operation::Keep => "keep",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Runs the operation of every line on its file, each paired with the name it gives the file
+pub fn bulk_rename(
+    froms: &[PathBuf],
+    tos: &[(Operation, PathBuf)],
+    options: &RenameOptions,
+) -> Result<Report, Error> {
+    let fs = RealFs::new();
+    let lines: Vec<usize> = (1..=froms.len()).collect();
+    run(&fs, &plan(&fs, froms, tos, &lines, options)?, options)
+}
 
-pub fn bulk_rename(froms: &Vec<String>, tos: &Vec<String>, is_demo: bool) -> Result<i32, String> {
+// The steps worked out for a batch, in the order they run
+#[derive(Clone, Debug)]
+pub struct Planned {
+    pub steps: Vec<Step>,
+    // Files that are only removed to make room for others, rather than deleted by a line
+    pub cleared: Vec<PathBuf>,
+    // Lines that the conflict policy leaves as they are
+    pub skipped: Vec<(usize, Operation, Rename)>,
+}
+
+impl Planned {
+    // Whether a step changes a file of the batch, rather than parking a file under a temporary
+    // name or clearing its target out of the way
+    fn changes_file(&self, step: &Step) -> bool {
+        match *step {
+            Step::Delete { ref path, .. } | Step::Trash { ref path, .. } => {
+                !self.cleared.contains(path)
+            }
+            _ => !is_temporary_move(step),
+        }
+    }
+}
+
+/// What running a batch did to its files
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Steps that renamed, copied, linked or deleted a file, in the order they ran
+    pub renamed: Vec<Step>,
+    /// Lines left as they are by the conflict policy, with the operation they asked for
+    pub skipped: Vec<(usize, Operation, Rename)>,
+    /// Steps that failed, with the reason. The files they were to change are left where the
+    /// steps before them put them.
+    pub failed: Vec<(Step, Error)>,
+    /// Every step that ran, temporary moves included, as recorded in the journal
+    pub steps: Vec<Step>,
+    /// Id of the journal batch the steps are recorded in, for undoing them later
+    pub batch: Option<String>,
+    /// Problems that did not stop the batch, such as a journal that could not be written
+    pub warnings: Vec<String>,
+}
+
+impl Report {
+    /// The number of files changed. An exchange changes two.
+    pub fn files(&self) -> usize {
+        self.renamed
+            .iter()
+            .map(|step| match *step {
+                Step::Exchange(_) => 2,
+                _ => 1,
+            })
+            .sum()
+    }
+}
+
+// Works out the steps that carry out every line, without touching anything. All operations go
+// through the same conflict checks. Deletions run first, then renames, and copies and links
+// last, from wherever the renames have left their sources. Conflicts and skipped lines are
+// reported by the line number given for each file.
+pub fn plan(
+    fs: &dyn Filesystem,
+    froms: &[PathBuf],
+    tos: &[(Operation, PathBuf)],
+    lines: &[usize],
+    options: &RenameOptions,
+) -> Result<Planned, Error> {
     if froms.len() != tos.len() {
-        return Err("Error: renamed files does not match original files in length".to_string());
+        return Err(Error::LineCount {
+            expected: froms.len(),
+            found: tos.len(),
+        });
+    }
+
+    if options.on_conflict == ConflictPolicy::Abort {
+        check_collisions(tos, lines)?;
+    }
+
+    let mut renames: Vec<(usize, Rename)> = vec![];
+    let mut creations: Vec<(usize, Operation, Rename)> = vec![];
+    let mut deletions: Vec<(usize, PathBuf)> = vec![];
+    let mut stale_links: Vec<PathBuf> = vec![];
+    for ((from, &(op, ref to)), &line) in froms.iter().zip(tos.iter()).zip(lines.iter()) {
+        match op {
+            Operation::Rename => {
+                let rs = Rename::renames_for(from, to, options.rename_dirs);
+                renames.extend(rs.into_iter().map(|r| (line, r)));
+            }
+            Operation::Delete => deletions.push((line, from.clone())),
+            Operation::Keep => (),
+            // A copy or link under its own name is no change either, and neither is a link that
+            // an earlier run has made already
+            _ if from == to || is_current_link(fs, op, from, to, options.relative_links) => (),
+            _ => {
+                // A link from an earlier run is made anew, in case it was asked for in another form
+                if is_stale_link(fs, op, from, to) {
+                    stale_links.push(to.clone());
+                }
+                creations.push((line, op, Rename::new(from, to)));
+            }
+        }
     }
 
-    let mut renames: Vec<Rename> = vec![];
-    for (from, to) in froms.iter().zip(tos.iter()) {
-        match Rename::renames_for(from, to) {
-            Ok(rs) => renames.extend(rs.iter().cloned()),
-            Err(msg) => return Err(msg),
+    let claims: Vec<(usize, Operation, Rename)> = merge_renames(&renames)?
+        .into_iter()
+        .map(|(line, r)| (line, Operation::Rename, r))
+        .chain(without_covered(creations))
+        .collect();
+    check_deletions(&claims, &deletions)?;
+
+    let deleted: Vec<PathBuf> = deletions.into_iter().map(|(_, path)| path).collect();
+    let removed: Vec<PathBuf> = deleted.iter().chain(stale_links.iter()).cloned().collect();
+    let resolved = conflict::resolve(
+        fs,
+        &claims,
+        &removed,
+        options.on_conflict,
+        options.prompt.as_ref(),
+    )?;
+
+    let (renames, creations): (Vec<_>, Vec<_>) = resolved
+        .claims
+        .into_iter()
+        .partition(|&(op, _)| op == Operation::Rename);
+    let renames: Vec<Rename> = renames.into_iter().map(|(_, r)| r).collect();
+    let renames = build_plan(&renames);
+
+    // A copy or link made inside another one needs its directory made first
+    let mut creations: Vec<(Operation, Rename)> = creations
+        .into_iter()
+        .map(|(op, r)| (op, r.with_from(resolve_path(&renames, &r.from))))
+        .collect();
+    creations.sort_by_key(|(_, r)| r.to.components().count());
+
+    // Deletions run first, deepest first, so that their names are free for the renames and a
+    // directory is emptied before it is deleted. With a trash, files that renames would replace
+    // are moved there beforehand, otherwise the renames replace them on their own. Copies and
+    // links cannot replace anything, so what they replace is always removed first.
+    let mut removals: Vec<(PathBuf, bool)> = deleted
+        .into_iter()
+        .map(|path| (path, options.recursive_delete))
+        .collect();
+    let mut overwritten: Vec<PathBuf> = vec![];
+    let mut cleared: Vec<PathBuf> = stale_links.clone();
+    for path in resolved.replaced {
+        if options.trash.is_some() || creations.iter().any(|(_, r)| r.to == path) {
+            cleared.push(path.clone());
+            removals.push((path, false));
+        } else {
+            overwritten.push(path);
         }
     }
+    removals.extend(stale_links.into_iter().map(|path| (path, false)));
+
+    let mut plan: Vec<Step> = vec![];
+    let mut taken: HashSet<PathBuf> = HashSet::new();
+    for (path, recursive) in removals {
+        plan.push(match options.trash {
+            Some(ref home) => {
+                let trash = trash::dir_for(fs, &path, home).map_err(|e| {
+                    Error::io(format!("move {} to the trash", path.display()), &path, e)
+                })?;
+                Step::Trash {
+                    trashed: trash::name_in(fs, &trash, &path, &mut taken),
+                    path,
+                    recursive,
+                }
+            }
+            None => Step::Delete { path, recursive },
+        });
+    }
+    plan.sort_by_key(|step| Reverse(step.path().components().count()));
+    plan.extend(rename_steps(renames, &overwritten));
+    for (op, r) in creations {
+        plan.push(match op {
+            Operation::Symlink => {
+                let target = link_target(fs, &r.from, &r.to, options.relative_links)
+                    .map_err(|e| Error::io(format!("link to {}", r.from.display()), &r.from, e))?;
+                Step::Symlink(r.with_from(target))
+            }
+            Operation::Hardlink => Step::Hardlink(r),
+            _ => Step::Copy(r),
+        });
+    }
 
-    let (conflicting, non_conflicting) = split_by_rename_conflicts(&renames);
+    Ok(Planned {
+        steps: plan,
+        cleared,
+        skipped: resolved.skipped,
+    })
+}
 
-    match do_bulk_rename(&non_conflicting, false, is_demo) {
-        Ok(count1) => match do_bulk_rename(&with_temporary_moves(&conflicting), false, is_demo) {
-            Ok(_) => Ok(count1 + conflicting.len() as i32 * 2),
-            Err(_) => Ok(count1),
+// Runs the steps of a plan and reports what they did. Failed steps are reported and the rest run
+// on, unless the batch is transactional.
+pub fn run(
+    fs: &dyn Filesystem,
+    planned: &Planned,
+    options: &RenameOptions,
+) -> Result<Report, Error> {
+    let plan = &planned.steps;
+    let mut warnings = vec![];
+    // The plan is logged before anything runs, so an interrupted batch can be recovered
+    let mut log = match options.journal {
+        Some(ref dir) if !plan.is_empty() => match journal::begin(fs, dir, plan) {
+            Ok(log) => Some(log),
+            Err(e) => {
+                warnings.push(format!("failed to write journal: {}", e));
+                None
+            }
         },
-        e => e,
+        _ => None,
+    };
+
+    let result = do_bulk_rename(fs, plan, options.transactional, log.as_mut());
+
+    let mut batch = None;
+    if let Some(mut log) = log {
+        let id = log.id().to_string();
+        warnings.extend(log.take_warnings());
+        match log.complete() {
+            Ok(()) => batch = Some(id),
+            Err(e) => warnings.push(format!("failed to write journal: {}", e)),
+        }
+    }
+
+    let (done, failed) = result?;
+    if options.prune_empty {
+        prune_empty_dirs(fs, &done);
     }
+
+    Ok(Report {
+        renamed: done
+            .iter()
+            .filter(|step| planned.changes_file(step))
+            .cloned()
+            .collect(),
+        skipped: planned.skipped.clone(),
+        failed,
+        steps: done,
+        batch,
+        warnings,
+    })
 }
 
-fn replace_filename(file_path: &str, name: &str) -> String {
-    let mut path = PathBuf::new();
-    path.push(file_path);
-    path.pop();
-    path.push(name);
-    path.to_str().unwrap().to_string()
+// Runs the steps of a plan against a copy of the files they touch, held in memory, to find out
+// what a real run would do: which steps fail and why, whether for a missing source, a file in the
+// way, a lack of permission or a directory that an earlier step has moved. Nothing is changed and
+// nothing is recorded in the journal. A transactional batch is reported as it would end: with the
+// step that stops it failed and every step before it undone.
+pub fn simulate(
+    fs: &dyn Filesystem,
+    planned: &Planned,
+    options: &RenameOptions,
+) -> Result<Report, Error> {
+    let paths: Vec<PathBuf> = planned
+        .steps
+        .iter()
+        .flat_map(Step::paths)
+        .map(Path::to_path_buf)
+        .collect();
+    let copy = MemoryFs::snapshot(fs, &paths)
+        .map_err(|e| Error::io("read the files to simulate the plan on", ".", e))?;
+
+    // Running on past a failure finds the same first failure that would stop the batch
+    let simulated = RenameOptions {
+        journal: None,
+        transactional: false,
+        ..options.clone()
+    };
+    let mut report = run(&copy, planned, &simulated)?;
+    if options.transactional && !report.failed.is_empty() {
+        report.failed.truncate(1);
+        report.renamed.clear();
+        report.steps.clear();
+    }
+    Ok(report)
 }
 
-fn with_temporary_moves(renames: &Vec<(Rename, Rename)>) -> Vec<Rename> {
-    use uuid::Uuid;
+// Lines under the same directory each imply the rename of that directory. Identical renames are
+// merged into one, keeping the line that first asked for it, while renaming the same path to two
+// different names is an error naming both lines.
+fn merge_renames(claims: &[(usize, Rename)]) -> Result<Vec<(usize, Rename)>, Error> {
+    let mut merged: Vec<(usize, Rename)> = vec![];
+    let mut by_source: HashMap<PathBuf, usize> = HashMap::new();
+
+    for &(line, ref r) in claims {
+        match by_source.entry(conflict::normalize(&r.from)) {
+            Entry::Occupied(e) => {
+                let (first, ref earlier) = merged[*e.get()];
+                if conflict::normalize(&earlier.to) != conflict::normalize(&r.to) {
+                    return Err(Error::conflict(
+                        vec![first, line],
+                        format!(
+                            "Contradictory renames of {}: line {} renames it to {}, line {} to {}",
+                            r.from.display(),
+                            first,
+                            earlier.to.display(),
+                            line,
+                            r.to.display()
+                        ),
+                    ));
+                }
+            }
+            Entry::Vacant(e) => {
+                e.insert(merged.len());
+                merged.push((line, r.clone()));
+            }
+        }
+    }
 
-    let mut non_conflicting: Vec<Rename> = vec![];
-    for (ref x, ref y) in renames {
-        let temp_file_name = format!("{}", Uuid::new_v4());
+    Ok(merged)
+}
 
-        non_conflicting.push(x.with_to(&replace_filename(&x.to, &temp_file_name)));
-        non_conflicting.push(y.clone());
-        non_conflicting.push(x.with_from(&replace_filename(&x.from, &temp_file_name)));
+// Turns planned renames into steps. A swap of two files through a temporary name becomes a single
+// exchange, and renames only replace files that the plan has decided to replace.
+fn rename_steps(renames: Vec<Rename>, overwritten: &[PathBuf]) -> Vec<Step> {
+    let mut steps: Vec<Step> = vec![];
+    let mut i = 0;
+
+    while i < renames.len() {
+        if let [ref park, ref first, ref last] = renames[i..renames.len().min(i + 3)] {
+            if is_temporary(&park.to)
+                && first.to == park.from
+                && last.from == park.to
+                && last.to == first.from
+            {
+                steps.push(Step::Exchange(Rename::new(&park.from, &first.from)));
+                i += 3;
+                continue;
+            }
+        }
+
+        let r = renames[i].clone();
+        steps.push(if overwritten.contains(&conflict::normalize(&r.to)) {
+            Step::Replace(r)
+        } else {
+            Step::Rename(r)
+        });
+        i += 1;
     }
 
-    non_conflicting
+    steps
 }
 
-fn split_by_rename_conflicts(renames: &Vec<Rename>) -> (Vec<(Rename, Rename)>, Vec<Rename>) {
-    let mut seen: HashMap<u64, Rename> = HashMap::new();
-    let mut conflicting_set: HashSet<Rename> = HashSet::new();
-    let mut conflicting: Vec<(Rename, Rename)> = vec![];
+// Files are parked under temporary names while a cycle of renames runs
+fn is_temporary(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with(TEMP_PREFIX))
+}
 
-    for r in renames {
-        let k = r.combined_hash();
-        if seen.contains_key(&k) {
-            let other = seen.get(&k).unwrap();
+// Parking a file under a temporary name is not counted as changing it
+fn is_temporary_move(step: &Step) -> bool {
+    match *step {
+        Step::Rename(ref r) | Step::Replace(ref r) => is_temporary(&r.to),
+        _ => false,
+    }
+}
 
-            if r != other {
-                conflicting_set.insert(r.clone());
-                conflicting_set.insert(other.clone());
+// Copying or linking a directory takes its contents along, so lines that would give the same
+// contents the same names inside the new directory have nothing left to do
+fn without_covered(
+    creations: Vec<(usize, Operation, Rename)>,
+) -> Vec<(usize, Operation, Rename)> {
+    let covered = |&(_, op, ref r): &(usize, Operation, Rename)| {
+        creations.iter().any(|&(_, other_op, ref other)| {
+            other_op == op
+                && other.from != r.from
+                && r.from
+                    .strip_prefix(&other.from)
+                    .is_ok_and(|rest| other.to.join(rest) == r.to)
+        })
+    };
+    creations.iter().filter(|c| !covered(c)).cloned().collect()
+}
 
-                conflicting.push((r.clone(), other.clone()));
+// Files inside a deleted directory would be gone before they could be renamed or copied
+fn check_deletions(
+    claims: &[(usize, Operation, Rename)],
+    deletions: &[(usize, PathBuf)],
+) -> Result<(), Error> {
+    for &(line, op, ref r) in claims {
+        for &(deleted_on, ref path) in deletions {
+            if r.from.starts_with(path) {
+                return Err(Error::conflict(
+                    vec![deleted_on, line],
+                    format!(
+                        "Line {} would {} {}, which is deleted with line {}",
+                        line,
+                        op,
+                        r.from.display(),
+                        deleted_on
+                    ),
+                ));
             }
-        } else {
-            seen.insert(k, r.clone());
         }
     }
+    Ok(())
+}
+
+// Orders renames into the steps to run, adding temporary moves to break cycles
+fn build_plan(renames: &[Rename]) -> Vec<Rename> {
+    let (moves, in_place): (Vec<Rename>, Vec<Rename>) =
+        renames.iter().cloned().partition(|r| r.is_move());
+
+    // A move whose source a rename in place takes over has to be out of the way before that
+    // rename runs. It is parked under a temporary name next to its source first, and moved on
+    // from there along with the other moves.
+    let targets: HashSet<PathBuf> = in_place.iter().map(|r| conflict::normalize(&r.to)).collect();
+    let mut plan: Vec<Rename> = vec![];
+    let moves: Vec<Rename> = moves
+        .into_iter()
+        .map(|r| {
+            if !targets.contains(&conflict::normalize(&r.from)) {
+                return r;
+            }
+            let temp_path = r.from.with_file_name(temp_name());
+            plan.push(r.with_to(&temp_path));
+            r.with_from(temp_path)
+        })
+        .collect();
+
+    for level in renames_by_depth(&in_place) {
+        let (ordered, cycles) = plan_renames(&level);
+
+        plan.extend(ordered);
+        plan.extend(with_temporary_moves(&cycles));
+    }
+
+    // Moves name their targets as they should be in the final tree, so they run after every
+    // rename in place, taking their sources from wherever the earlier steps have left them.
+    // Shallow moves go first, then a directory moved before its contents takes them along.
+    // A file whose directory was renamed by its own line may already be where it should be.
+    let mut moves: Vec<Rename> = moves
+        .iter()
+        .map(|r| r.with_from(resolve_path(&plan, &r.from)))
+        .filter(|r| r.from != r.to)
+        .collect();
+    moves.sort_by_key(|r| r.from.components().count());
+
+    let (ordered, cycles) = plan_renames(&moves);
+    let first_move = plan.len();
+    for r in ordered.into_iter().chain(with_temporary_moves(&cycles)) {
+        let from = resolve_path(&plan[first_move..], &r.from);
+        plan.push(r.with_from(&from));
+    }
+
+    plan
+}
+
+// Where a path from the original tree is once the given steps have run
+fn resolve_path(steps: &[Rename], path: &Path) -> PathBuf {
+    let mut resolved = path.to_path_buf();
+    for step in steps {
+        let rest = match resolved.strip_prefix(&step.from) {
+            Ok(rest) => rest.to_path_buf(),
+            Err(_) => continue,
+        };
+        resolved = if rest.as_os_str().is_empty() {
+            step.to.clone()
+        } else {
+            step.to.join(rest)
+        };
+    }
+    resolved
+}
 
-    let non_conflicting: Vec<Rename> = renames
+// Removes directories that moving files out of them has left empty, deepest first so that a
+// directory emptied along with one inside it goes too. Directories the batch did not move
+// anything out of are left alone, even if they are empty now, and so is the working directory
+// along with everything above it.
+fn prune_empty_dirs(fs: &dyn Filesystem, done: &[Step]) {
+    let cwd = fs.current_dir().unwrap_or_default();
+    let mut dirs: Vec<&Path> = done
         .iter()
-        .filter(|r| !conflicting_set.contains(&r))
-        .map(|r| r.clone())
+        .filter_map(|step| match *step {
+            Step::Rename(ref r) | Step::Replace(ref r) if r.is_move() => r.from.parent(),
+            _ => None,
+        })
+        .filter(|d| !d.as_os_str().is_empty() && !cwd.starts_with(cwd.join(d)))
         .collect();
+    dirs.sort_by_key(|d| Reverse(d.components().count()));
+    dirs.dedup();
 
-    (conflicting, non_conflicting)
+    for dir in dirs {
+        let _ = fs.remove_dir(dir);
+    }
 }
 
-// TODO: We need to be smarter about bulk renames
-// Some renames might have to be done earlier than others because when
-// directory renames are involved, there can be later dependencies later on for those
-fn do_bulk_rename(renames: &Vec<Rename>, early_exit: bool, is_demo: bool) -> Result<i32, String> {
-    let mut count = 0;
+/// Reverts a batch from the journal, the latest one that was not undone if no id is given. The
+/// report lists the steps that restored a file, and warns about files that cannot be restored.
+pub fn undo(fs: &dyn Filesystem, journal_dir: &Path, id: Option<&str>) -> Result<Report, Error> {
+    let batch = journal::load(journal_dir, id).map_err(Error::Batch)?;
+    if batch.undone {
+        return Err(Error::Batch(format!("Batch {} has already been undone", batch.id)));
+    }
+    if !batch.complete {
+        return Err(Error::Batch(format!(
+            "Batch {} was interrupted, recover it with --recover",
+            batch.id
+        )));
+    }
+
+    let mut steps: Vec<Step> = vec![];
+    let mut warnings = vec![];
+    for step in batch.completed().iter().rev() {
+        match step.reversed() {
+            Some(reversed) => steps.push(reversed),
+            None => warnings.push(format!("cannot restore deleted {}", step.path().display())),
+        }
+        if let Step::Replace(ref r) = *step {
+            warnings.push(format!("cannot restore the file replaced at {}", r.to.display()));
+        }
+    }
+    let completed = moves_in(&batch.completed());
+    // Copies and links are removed before the renames are reverted
+    let created: Vec<PathBuf> = batch
+        .completed()
+        .iter()
+        .filter_map(|step| match *step {
+            Step::Copy(ref r) | Step::Symlink(ref r) | Step::Hardlink(ref r) => Some(r.to.clone()),
+            _ => None,
+        })
+        .collect();
+
+    // Replaying a batch backwards only restores it if every file is still where the batch
+    // left it, and nothing new has appeared at the names it is going to restore.
+    for step in batch.completed() {
+        step.check_made(fs)
+            .map_err(|e| Error::Batch(format!("Cannot undo batch {}: {}", batch.id, e)))?;
+    }
+    for (current, original) in net_renames(&completed) {
+        if !fs.exists(&current) {
+            return Err(Error::Batch(format!(
+                "Cannot undo batch {}: {} is no longer there",
+                batch.id,
+                current.display()
+            )));
+        }
+        if fs.exists(&original)
+            && !completed.iter().any(|r| r.to == original)
+            && !created.contains(&original)
+        {
+            return Err(Error::Batch(format!(
+                "Cannot undo batch {}: {} already exists",
+                batch.id,
+                original.display()
+            )));
+        }
+    }
+
+    let (done, _) = do_bulk_rename(fs, &steps, true, None)?;
+    journal::mark_undone(journal_dir, &batch.id).map_err(|e| {
+        Error::Batch(format!("Undid batch {} but failed to mark it: {}", batch.id, e))
+    })?;
+
+    Ok(Report {
+        renamed: done.iter().filter(|step| !is_temporary_move(step)).cloned().collect(),
+        steps: done,
+        batch: Some(batch.id),
+        warnings,
+        ..Report::default()
+    })
+}
+
+/// Brings batches that were interrupted part way to a consistent state, either by running their
+/// remaining steps or by reverting the completed ones. Each batch gets a report of the steps it
+/// ran or undid, and of those that failed.
+pub fn recover(
+    fs: &dyn Filesystem,
+    journal_dir: &Path,
+    finish: bool,
+) -> Result<Vec<Report>, Error> {
+    let batches = journal::interrupted(journal_dir).map_err(Error::Batch)?;
+    if batches.is_empty() {
+        return Err(Error::Batch("No interrupted batch found in the journal".to_string()));
+    }
+
+    let mut reports = vec![];
+    for mut batch in batches {
+        // The process may have died after a step but before it was marked. Steps run in order,
+        // so only the one after the last marked step can be in that state.
+        let next = batch.done.iter().rposition(|&d| d).map_or(0, |i| i + 1);
+        if let Some(step) = batch.steps.get(next) {
+            let ran = match *step {
+                Step::Exchange(ref r) => exchange_ran(fs, &batch, next, r)?,
+                _ => step.has_run(fs),
+            };
+            if ran {
+                batch.done[next] = true;
+            }
+        }
+
+        let mut log = journal::resume(journal_dir, &batch).map_err(|e| {
+            Error::Batch(format!("Failed to open journal batch {}: {}", batch.id, e))
+        })?;
+
+        let (done, failed) = if finish {
+            let pending: Vec<Step> = batch
+                .steps
+                .iter()
+                .zip(batch.done.iter())
+                .filter(|&(_, &done)| !done)
+                .map(|(r, _)| r.clone())
+                .collect();
 
-    for rename in renames {
-        let ok = rename.do_rename(is_demo);
-        if ok {
-            count += 1;
+            do_bulk_rename(fs, &pending, false, Some(&mut log))?
         } else {
-            if early_exit {
-                return Err(format!("Failed to rename: {:?}", rename));
-            } else {
-                println!("Warning - failed to rename: {:?}", rename);
+            let rollback = roll_back(fs, &batch.completed(), Some(&mut log));
+            let failed = rollback
+                .failed
+                .into_iter()
+                .map(|(step, e)| {
+                    let error = Error::io(format!("undo {}", step), step.path(), e);
+                    (step, error)
+                })
+                .collect();
+            (rollback.undone, failed)
+        };
+
+        let warnings = log.take_warnings();
+        log.complete().map_err(|e| {
+            Error::Batch(format!("Failed to update journal batch {}: {}", batch.id, e))
+        })?;
+
+        reports.push(Report {
+            renamed: done.iter().filter(|step| !is_temporary_move(step)).cloned().collect(),
+            failed,
+            steps: done,
+            batch: Some(batch.id),
+            warnings,
+            ..Report::default()
+        });
+    }
+
+    Ok(reports)
+}
+
+// Whether an interrupted exchange ran, by which name the file recorded at its source is under.
+// With no record of it, or the file under neither name, there is no telling, and the batch is
+// left to be put right by hand.
+fn exchange_ran(
+    fs: &dyn Filesystem,
+    batch: &journal::Batch,
+    i: usize,
+    r: &Rename,
+) -> Result<bool, Error> {
+    let is_source = |path: &Path| match (fs.symlink_metadata(path), batch.exchanged.get(&i)) {
+        (Ok(md), Some(&(dev, ino))) => ino != 0 && md.dev == dev && md.ino == ino,
+        _ => false,
+    };
+
+    if is_source(&r.from) {
+        Ok(false)
+    } else if is_source(&r.to) {
+        Ok(true)
+    } else {
+        Err(Error::Batch(format!(
+            "Cannot recover batch {}: unsure whether {} and {} were swapped, check them by hand",
+            batch.id,
+            r.from.display(),
+            r.to.display()
+        )))
+    }
+}
+
+// The files moved by a sequence of steps, whether renamed or moved to and from the trash
+fn moves_in(steps: &[Step]) -> Vec<Rename> {
+    steps.iter().flat_map(Step::moves).collect()
+}
+
+// Collapses a sequence of renames into where each file ended up, paired with where it started
+fn net_renames(renames: &[Rename]) -> Vec<(PathBuf, PathBuf)> {
+    let mut origins: Vec<(PathBuf, PathBuf)> = vec![];
+
+    for r in renames {
+        let origin = match origins.iter().position(|(current, _)| *current == r.from) {
+            Some(i) => origins.remove(i).1,
+            None => r.from.clone(),
+        };
+        origins.push((r.to.clone(), origin));
+    }
+
+    origins.retain(|(current, origin)| current != origin);
+    origins
+}
+
+// Every line claims its target, including lines left unchanged. A target claimed by more than
+// one line would have all but the last of them overwritten, so every such target is reported
+// together with the lines claiming it.
+fn check_collisions(tos: &[(Operation, PathBuf)], lines: &[usize]) -> Result<(), Error> {
+    let mut claims: Vec<(PathBuf, Vec<usize>)> = vec![];
+    let mut by_target: HashMap<PathBuf, usize> = HashMap::new();
+
+    let targets = tos
+        .iter()
+        .zip(lines.iter())
+        .filter(|&(&(op, _), _)| op != Operation::Delete)
+        .map(|((_, to), &line)| (line, to));
+    for (line, to) in targets {
+        let target = conflict::normalize(to);
+        match by_target.entry(target.clone()) {
+            Entry::Occupied(e) => claims[*e.get()].1.push(line),
+            Entry::Vacant(e) => {
+                e.insert(claims.len());
+                claims.push((target, vec![line]));
+            }
+        }
+    }
+
+    claims.retain(|(_, lines)| lines.len() > 1);
+    if claims.is_empty() {
+        return Ok(());
+    }
+
+    let mut msg = "Several lines rename to the same target:".to_string();
+    let mut all_lines: Vec<usize> = vec![];
+    for (target, mut lines) in claims {
+        lines.sort();
+        all_lines.extend(&lines);
+        let lines: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        msg.push_str(&format!("\n  {}: lines {}", target.display(), lines.join(", ")));
+    }
+    all_lines.sort();
+    Err(Error::conflict(all_lines, msg))
+}
+
+// A rename in place swaps a single path component and keeps its parent as it was in the original
+// tree. Running the deepest renames first means a parent is only renamed after everything below
+// it has been renamed in place, so the paths of later renames are never invalidated by earlier
+// ones.
+fn renames_by_depth(renames: &[Rename]) -> Vec<Vec<Rename>> {
+    let mut levels: Vec<(usize, Vec<Rename>)> = vec![];
+
+    for r in renames {
+        let depth = r.from.components().count();
+        match levels.iter().position(|&(d, _)| d == depth) {
+            Some(i) => levels[i].1.push(r.clone()),
+            None => levels.push((depth, vec![r.clone()])),
+        }
+    }
+
+    levels.sort_by_key(|&(depth, _)| Reverse(depth));
+    levels.into_iter().map(|(_, level)| level).collect()
+}
+
+// Each cycle is given so that every rename targets the source of the next one, wrapping around
+// at the end. Moving the first source out of the way frees the target of the last rename, which
+// lets the rest of the cycle run backwards before the first file is moved into place.
+fn with_temporary_moves(cycles: &[Vec<Rename>]) -> Vec<Rename> {
+    let mut non_conflicting: Vec<Rename> = vec![];
+    for cycle in cycles {
+        let first = &cycle[0];
+        let temp_path = first.from.with_file_name(temp_name());
+
+        non_conflicting.push(first.with_to(&temp_path));
+        non_conflicting.extend(cycle[1..].iter().rev().cloned());
+        non_conflicting.push(first.with_from(&temp_path));
+    }
+
+    non_conflicting
+}
+
+fn temp_name() -> String {
+    use uuid::Uuid;
+
+    format!("{}{}", TEMP_PREFIX, Uuid::new_v4())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    Pending,
+    InProgress,
+    Done,
+}
+
+// Renames form a dependency graph in which a rename has to wait for the rename whose source is
+// its target, since that file must move out of the way first. Sources are unique and so are the
+// targets of a valid mapping, hence every component of the graph is either a chain or a simple
+// cycle. Chains are returned in an order that is safe to run as is. Cycles cannot be ordered and
+// are returned separately, each needing exactly one temporary move to break it.
+fn plan_renames(renames: &[Rename]) -> (Vec<Rename>, Vec<Vec<Rename>>) {
+    let mut nodes: Vec<&Rename> = vec![];
+    let mut by_source: HashMap<&Path, usize> = HashMap::new();
+
+    for r in renames {
+        if let Entry::Vacant(e) = by_source.entry(&r.from) {
+            e.insert(nodes.len());
+            nodes.push(r);
+        }
+    }
+
+    let blocker = |i: usize| by_source.get(nodes[i].to.as_path()).cloned();
+
+    let mut state = vec![Visit::Pending; nodes.len()];
+    let mut ordered: Vec<Rename> = vec![];
+    let mut cycles: Vec<Vec<Rename>> = vec![];
+
+    for start in 0..nodes.len() {
+        let mut path: Vec<usize> = vec![];
+        let mut current = Some(start);
+
+        while let Some(i) = current {
+            match state[i] {
+                Visit::Pending => {
+                    state[i] = Visit::InProgress;
+                    path.push(i);
+                    current = blocker(i);
+                }
+                Visit::InProgress => {
+                    let pos = path.iter().position(|&j| j == i).unwrap();
+                    let cycle = path.split_off(pos);
+                    for &j in &cycle {
+                        state[j] = Visit::Done;
+                    }
+                    cycles.push(cycle.iter().map(|&j| nodes[j].clone()).collect());
+                    current = None;
+                }
+                Visit::Done => current = None,
+            }
+        }
+
+        for &i in path.iter().rev() {
+            state[i] = Visit::Done;
+            ordered.push(nodes[i].clone());
+        }
+    }
+
+    (ordered, cycles)
+}
+
+// Steps that failed without stopping the batch, with the reason
+type Failures = Vec<(Step, Error)>;
+
+fn do_bulk_rename(
+    fs: &dyn Filesystem,
+    steps: &[Step],
+    early_exit: bool,
+    mut log: Option<&mut journal::Log>,
+) -> Result<(Vec<Step>, Failures), Error> {
+    let mut done: Vec<Step> = vec![];
+    let mut failed: Failures = vec![];
+
+    for step in steps {
+        if let Step::Exchange(ref r) = *step {
+            log_step(&mut log, |log| log.exchange_started(step, &fs.symlink_metadata(&r.from)?));
+        }
+        match step.run(fs) {
+            Ok(()) => {
+                log_step(&mut log, |log| log.step_done(step));
+                done.push(step.clone());
+            }
+            Err(e) => {
+                let error = Error::io(step.to_string(), step.path(), e);
+                if early_exit {
+                    return Err(Error::RolledBack {
+                        error: Box::new(error),
+                        rollback: roll_back(fs, &done, log),
+                    });
+                }
+                failed.push((step.clone(), error));
+            }
+        }
+    }
+
+    Ok((done, failed))
+}
+
+fn log_step<F>(log: &mut Option<&mut journal::Log>, f: F)
+where
+    F: FnOnce(&mut journal::Log) -> io::Result<()>,
+{
+    if let Some(ref mut log) = *log {
+        if let Err(e) = f(log) {
+            log.warn(format!("failed to update journal: {}", e));
+        }
+    }
+}
+
+/// What undoing the steps of a failed transactional batch did, last step first
+#[derive(Debug, Default)]
+pub struct Rollback {
+    /// Steps that were undone
+    pub undone: Vec<Step>,
+    /// Steps that could not be undone, with the reason. Their files are left as the steps made
+    /// them.
+    pub failed: Vec<(Step, io::Error)>,
+}
+
+impl fmt::Display for Rollback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Rolled back {} renames", self.undone.len())?;
+        for r in &self.undone {
+            write!(f, "\n  undone: {}", r)?;
+        }
+        for (r, e) in &self.failed {
+            write!(f, "\n  could not undo: {} ({})", r, e)?;
+        }
+        Ok(())
+    }
+}
+
+// Undoes completed steps last to first. This reverses the temporary moves of a cycle too, as
+// they are part of the same sequence. Deleted files cannot be brought back, trashed ones can.
+// Neither can files replaced without a trash, so a rename that replaced one is reverted but
+// still counts as not undone.
+fn roll_back(
+    fs: &dyn Filesystem,
+    done: &[Step],
+    mut log: Option<&mut journal::Log>,
+) -> Rollback {
+    let mut rollback = Rollback::default();
+
+    for step in done.iter().rev() {
+        let result = match step.reversed() {
+            Some(reversed) => step.check_made(fs).and_then(|()| reversed.run(fs)),
+            None => Err(io::Error::other("deleted files cannot be restored")),
+        };
+        match result {
+            Ok(()) => {
+                log_step(&mut log, |log| log.step_undone(step));
+                match *step {
+                    // The file is back under its name, but the one it replaced is gone for good
+                    Step::Replace(ref r) => {
+                        let e = io::Error::other(format!(
+                            "the file it replaced at {} cannot be restored",
+                            r.to.display()
+                        ));
+                        rollback.failed.push((step.clone(), e));
+                    }
+                    _ => rollback.undone.push(step.clone()),
+                }
             }
+            Err(e) => rollback.failed.push((step.clone(), e)),
         }
     }
 
-    Ok(count)
+    rollback
 }
 
+/// A single step of a plan, as it is run and recorded in the journal
 #[derive(Debug, PartialEq, Clone, Eq, Hash)]
-struct Rename {
-    from: String,
-    to: String,
+pub enum Step {
+    Rename(Rename),
+    /// Renames over a file that the plan has decided to replace
+    Replace(Rename),
+    /// Swaps two files
+    Exchange(Rename),
+    /// Directories are only deleted when empty, unless recursive
+    Delete { path: PathBuf, recursive: bool },
+    /// Moves a file to the name picked for it in a trash directory
+    Trash {
+        path: PathBuf,
+        trashed: PathBuf,
+        recursive: bool,
+    },
+    /// Takes a file back out of the trash
+    Restore { trashed: PathBuf, path: PathBuf },
+    /// Copies a file or directory tree, leaving the source in place
+    Copy(Rename),
+    /// Makes a symlink at the target, its source being what the link contains
+    Symlink(Rename),
+    /// Links the target name to the source, which is left in place
+    Hardlink(Rename),
 }
 
-impl Rename {
-    fn new(from: &str, to: &str) -> Self {
-        Rename {
-            from: from.to_owned(),
-            to: to.to_owned(),
+impl Step {
+    /// The path the step acts on
+    pub fn path(&self) -> &Path {
+        match *self {
+            Step::Rename(ref r)
+            | Step::Replace(ref r)
+            | Step::Exchange(ref r)
+            | Step::Copy(ref r)
+            | Step::Symlink(ref r)
+            | Step::Hardlink(ref r) => &r.from,
+            Step::Delete { ref path, .. } | Step::Trash { ref path, .. } => path,
+            Step::Restore { ref trashed, .. } => trashed,
+        }
+    }
+
+    fn run(&self, fs: &dyn Filesystem) -> io::Result<()> {
+        match *self {
+            Step::Rename(ref r) => r.do_rename(fs, false),
+            Step::Replace(ref r) => r.do_rename(fs, true),
+            Step::Exchange(ref r) => fs.exchange(&r.from, &r.to),
+            Step::Copy(ref r) => {
+                create_parent(fs, &r.to)?;
+                fs.copy(&r.from, &r.to)
+            }
+            Step::Symlink(ref r) => {
+                create_parent(fs, &r.to)?;
+                fs.symlink(&r.from, &r.to)
+            }
+            Step::Hardlink(ref r) => {
+                create_parent(fs, &r.to)?;
+                fs.hard_link(&r.from, &r.to)
+            }
+            Step::Delete {
+                ref path,
+                recursive,
+            } => {
+                if !fs.symlink_metadata(path)?.is_dir() {
+                    fs.remove_file(path)
+                } else if recursive {
+                    fs.remove_dir_all(path)
+                } else {
+                    fs.remove_dir(path)
+                }
+            }
+            Step::Trash {
+                ref path,
+                ref trashed,
+                recursive,
+            } => {
+                let md = fs.symlink_metadata(path)?;
+                if md.is_dir() && !recursive && !fs.read_dir(path)?.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::DirectoryNotEmpty,
+                        "Directory not empty",
+                    ));
+                }
+                fs.trash(path, trashed)
+            }
+            Step::Restore {
+                ref trashed,
+                ref path,
+            } => fs.restore(trashed, path),
+        }
+    }
+
+    // The step that undoes this one, if it can be undone
+    fn reversed(&self) -> Option<Step> {
+        match *self {
+            // Only the rename is undone, the file replaced is gone and its name is free again
+            Step::Rename(ref r) | Step::Replace(ref r) => Some(Step::Rename(r.reversed())),
+            Step::Exchange(_) => Some(self.clone()),
+            Step::Delete { .. } => None,
+            Step::Trash {
+                ref path,
+                ref trashed,
+                ..
+            } => Some(Step::Restore {
+                trashed: trashed.clone(),
+                path: path.clone(),
+            }),
+            Step::Restore {
+                ref trashed,
+                ref path,
+            } => Some(Step::Trash {
+                path: path.clone(),
+                trashed: trashed.clone(),
+                recursive: true,
+            }),
+            // Removing a copy or link leaves the source as it was
+            Step::Copy(ref r) | Step::Symlink(ref r) | Step::Hardlink(ref r) => {
+                Some(Step::Delete {
+                    path: r.to.clone(),
+                    recursive: true,
+                })
+            }
+        }
+    }
+
+    // Every path the step looks at or changes. The source of a symlink is only what the link
+    // contains, and is left out.
+    fn paths(&self) -> Vec<&Path> {
+        match *self {
+            Step::Rename(ref r)
+            | Step::Replace(ref r)
+            | Step::Exchange(ref r)
+            | Step::Copy(ref r)
+            | Step::Hardlink(ref r) => vec![&r.from, &r.to],
+            Step::Symlink(ref r) => vec![&r.to],
+            Step::Delete { ref path, .. } => vec![path],
+            Step::Trash {
+                ref path,
+                ref trashed,
+                ..
+            }
+            | Step::Restore {
+                ref trashed,
+                ref path,
+            } => vec![path, trashed],
         }
     }
 
-    fn combined_hash(&self) -> u64 {
-        fn hash_str(s: &str) -> u64 {
-            let mut h = DefaultHasher::new();
-            s.hash(&mut h);
-            h.finish()
+    // Where the step moves files from and to, in order
+    fn moves(&self) -> Vec<Rename> {
+        match *self {
+            Step::Rename(ref r) | Step::Replace(ref r) => vec![r.clone()],
+            // As if through a temporary name, which the empty path stands for
+            Step::Exchange(ref r) => vec![
+                r.with_to(PathBuf::new()),
+                r.reversed(),
+                r.with_from(PathBuf::new()),
+            ],
+            Step::Delete { .. } | Step::Copy(_) | Step::Symlink(_) | Step::Hardlink(_) => vec![],
+            Step::Trash {
+                ref path,
+                ref trashed,
+                ..
+            } => vec![Rename::new(path, trashed)],
+            Step::Restore {
+                ref trashed,
+                ref path,
+            } => vec![Rename::new(trashed, path)],
         }
+    }
 
-        let (combined, _) = hash_str(&self.from).overflowing_add(hash_str(&self.to));
-        combined
+    // Whether the step has taken effect, judging by the files it acts on. Both files are still
+    // there after an exchange, which `exchange_ran` tells apart by the journal instead.
+    fn has_run(&self, fs: &dyn Filesystem) -> bool {
+        match *self {
+            Step::Copy(ref r) | Step::Symlink(ref r) | Step::Hardlink(ref r) => fs.exists(&r.to),
+            Step::Delete { ref path, .. } => !fs.exists(path),
+            _ => self
+                .moves()
+                .iter()
+                .all(|r| !fs.exists(&r.from) && fs.exists(&r.to)),
+        }
     }
 
-    fn do_rename(&self, is_demo: bool) -> bool {
-        if is_demo {
-            println!("{} -> {}", self.from, self.to);
-            true
+    // Undoing a copy or link deletes it, so it has to still be what the step made: a link to the
+    // same file, or a copy of the same kind and size as its source. Anything else is a file put
+    // there since, and is left alone.
+    fn check_made(&self, fs: &dyn Filesystem) -> io::Result<()> {
+        let (r, made) = match *self {
+            Step::Copy(ref r) => {
+                let (copy, source) = (fs.symlink_metadata(&r.to)?, fs.symlink_metadata(&r.from)?);
+                (r, copy.kind == source.kind && (copy.is_dir() || copy.size == source.size))
+            }
+            Step::Symlink(ref r) => {
+                let link = fs.symlink_metadata(&r.to)?;
+                (r, link.is_symlink() && fs.read_link(&r.to)? == r.from)
+            }
+            Step::Hardlink(ref r) => {
+                let (link, source) = (fs.symlink_metadata(&r.to)?, fs.symlink_metadata(&r.from)?);
+                (r, link.is_same_file(&source))
+            }
+            _ => return Ok(()),
+        };
+
+        if made {
+            Ok(())
         } else {
-            let status = fs::rename(&self.from, &self.to);
-            status.is_ok()
+            Err(io::Error::other(format!(
+                "{} has changed since the {} was made",
+                r.to.display(),
+                match *self {
+                    Step::Copy(_) => "copy",
+                    _ => "link",
+                }
+            )))
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Step::Rename(ref r) => write!(f, "rename {}", r),
+            Step::Replace(ref r) => write!(f, "rename {}, replacing it", r),
+            Step::Exchange(ref r) => {
+                write!(f, "swap {} <-> {}", r.from.display(), r.to.display())
+            }
+            Step::Delete {
+                ref path,
+                recursive: true,
+            } => write!(f, "delete {} and its contents", path.display()),
+            Step::Delete { ref path, .. } => write!(f, "delete {}", path.display()),
+            Step::Trash {
+                ref path,
+                recursive: true,
+                ..
+            } => write!(f, "trash {} and its contents", path.display()),
+            Step::Trash { ref path, .. } => write!(f, "trash {}", path.display()),
+            Step::Restore {
+                ref trashed,
+                ref path,
+            } => write!(f, "restore {} -> {}", trashed.display(), path.display()),
+            Step::Copy(ref r) => write!(f, "copy {}", r),
+            Step::Symlink(ref r) => write!(f, "symlink {}", r),
+            Step::Hardlink(ref r) => write!(f, "hardlink {}", r),
+        }
+    }
+}
+
+// What a symlink at `link` contains to point to `file`. Absolute targets keep working wherever
+// the link is moved, relative ones as long as the link and its file are moved together.
+fn link_target(
+    fs: &dyn Filesystem,
+    file: &Path,
+    link: &Path,
+    relative: bool,
+) -> io::Result<PathBuf> {
+    let file = absolute(fs, file)?;
+    if !relative {
+        return Ok(file);
+    }
+
+    let dir = absolute(fs, link)?.parent().map(Path::to_path_buf).unwrap_or_default();
+    let common = file
+        .components()
+        .zip(dir.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut target = PathBuf::new();
+    for _ in dir.components().skip(common) {
+        target.push("..");
+    }
+    for component in file.components().skip(common) {
+        target.push(component);
+    }
+    Ok(target)
+}
+
+// Resolves `.` and `..` by name only, without following symlinks
+pub fn absolute(fs: &dyn Filesystem, path: &Path) -> io::Result<PathBuf> {
+    let mut absolute = PathBuf::new();
+    for component in fs.current_dir()?.join(path).components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            component => absolute.push(component),
+        }
+    }
+    Ok(absolute)
+}
+
+// Whether `to` already is the link that would be made to `from`
+fn is_current_link(
+    fs: &dyn Filesystem,
+    op: Operation,
+    from: &Path,
+    to: &Path,
+    relative: bool,
+) -> bool {
+    match op {
+        Operation::Symlink => match (fs.read_link(to), link_target(fs, from, to, relative)) {
+            (Ok(contents), Ok(target)) => contents == target,
+            _ => false,
+        },
+        Operation::Hardlink => match (fs.symlink_metadata(from), fs.symlink_metadata(to)) {
+            (Ok(file), Ok(link)) => file.is_same_file(&link),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// Whether `to` is a link an earlier run made to `from` that is to be made anew, such as a
+// symlink to the file by its absolute path where a relative one is asked for. Anything else at
+// `to` is some other file, which only the conflict policy may replace.
+fn is_stale_link(fs: &dyn Filesystem, op: Operation, from: &Path, to: &Path) -> bool {
+    match op {
+        Operation::Symlink => match fs.read_link(to) {
+            Ok(contents) => [false, true]
+                .iter()
+                .any(|&relative| link_target(fs, from, to, relative).is_ok_and(|t| t == contents)),
+            Err(_) => false,
+        },
+        Operation::Hardlink => match (fs.symlink_metadata(from), fs.symlink_metadata(to)) {
+            (Ok(file), Ok(link)) => file.is_same_file(&link),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// Copies and links may target directories that do not exist yet
+fn create_parent(fs: &dyn Filesystem, path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && !fs.exists(parent) => {
+            fs.create_dir_all(parent)
         }
+        _ => Ok(()),
     }
+}
 
-    fn rename_sequence(from: &str, to: &str) -> Result<Vec<Rename>, String> {
+#[derive(Debug, PartialEq, Clone, Eq, Hash)]
+pub struct Rename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl Rename {
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Self {
+        Rename {
+            from: from.as_ref().to_path_buf(),
+            to: to.as_ref().to_path_buf(),
+        }
+    }
+
+    // Renames only replace an existing target when `replace` is set. Otherwise a file that has
+    // appeared at the target since the plan was made is left alone and the rename fails.
+    fn do_rename(&self, fs: &dyn Filesystem, replace: bool) -> io::Result<()> {
+        // Moves may target directories that do not exist yet
+        if fs.exists(&self.from) {
+            create_parent(fs, &self.to)?;
+        }
+        fs.rename(&self.from, &self.to, replace)
+    }
+
+    fn rename_sequence(from: &Path, to: &Path) -> Result<Vec<Rename>, String> {
         let mut renames: Vec<Rename> = vec![];
 
-        let mut from_path = PathBuf::from(from);
-        let mut to_path = PathBuf::from(to);
+        let mut from_path = from.to_path_buf();
+        let mut to_path = to.to_path_buf();
         let mut from_path_finished;
         let mut to_path_finished;
 
@@ -162,26 +1411,7 @@ impl Rename {
 
             if f1 != t1 {
                 // We need a rename from f1 to t1
-                let p1 = from_path.parent();
-
-                if p1.is_none() {
-                    let fs1 = f1.unwrap().to_str().unwrap();
-                    let ts1 = t1.unwrap().to_str().unwrap();
-
-                    renames.push(Rename::new(fs1, ts1));
-                } else {
-                    let mut p = PathBuf::new();
-                    p.push(p1.unwrap());
-                    p.push(f1.unwrap());
-
-                    let fs1 = p.to_str().unwrap().to_owned();
-                    assert!(p.pop());
-
-                    p.push(t1.unwrap());
-                    let ts1 = p.to_str().unwrap();
-
-                    renames.push(Rename::new(&fs1, ts1));
-                }
+                renames.push(Rename::new(&from_path, from_path.with_file_name(t1.unwrap())));
             }
 
             from_path_finished = !from_path.pop();
@@ -197,50 +1427,82 @@ impl Rename {
         } else {
             Err(format!(
                 "Incompatible paths for renaming: {} -> {}",
-                from, to
+                from.display(),
+                to.display()
             ))
         }
     }
 
-    pub fn renames_for(from: &str, to: &str) -> Result<Vec<Rename>, String> {
-        Rename::rename_sequence(from, to)
-    }
+    /// The renames needed to take a file from one path to another. A change to a parent
+    /// component moves just the file into the other directory, unless `rename_dirs` asks for the
+    /// parent directory itself to be renamed, along with everything else in it.
+    pub fn renames_for(from: &Path, to: &Path, rename_dirs: bool) -> Vec<Rename> {
+        if !rename_dirs {
+            return if from == to {
+                vec![]
+            } else {
+                vec![Rename::new(from, to)]
+            };
+        }
 
-    fn with_from(&self, from: &str) -> Rename {
-        Rename {
-            from: from.to_string(),
-            to: self.to.clone(),
+        match Rename::rename_sequence(from, to) {
+            Ok(renames) => renames,
+            // Paths of different depths cannot be renamed component by component, so the edit
+            // becomes a move of the file itself
+            Err(_) => vec![Rename::new(from, to)],
         }
     }
 
-    fn with_to(&self, to: &str) -> Rename {
-        Rename {
-            from: self.from.clone(),
-            to: to.to_string(),
-        }
+    // A move changes the directory of a file, rather than renaming it in place
+    fn is_move(&self) -> bool {
+        self.from.parent() != self.to.parent()
+    }
+
+    fn reversed(&self) -> Rename {
+        Rename::new(&self.to, &self.from)
+    }
+
+    pub fn with_from<P: AsRef<Path>>(&self, from: P) -> Rename {
+        Rename::new(from, &self.to)
+    }
+
+    pub fn with_to<P: AsRef<Path>>(&self, to: P) -> Rename {
+        Rename::new(&self.from, to)
+    }
+}
+
+impl fmt::Display for Rename {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} -> {}", self.from.display(), self.to.display())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Rename;
+    use super::{ConflictPolicy, Error, Operation, Rename, RenameOptions, Step};
+    use conflict::Conflict;
+    use copy;
+    use escape;
+    use filesystem::{Filesystem, RealFs};
+    use journal;
     use std::env;
     use std::fs::{self, File};
     use std::io::prelude::*;
     use std::panic;
     use std::path::{Path, PathBuf};
+    use std::sync::Arc;
 
     // Run a test with given setup before the test and teardown after the test.
     // Should ensure that setup and teardown code does not panic
     #[allow(dead_code)]
-    fn run_test_with_setup<S, C, T>(setup: S, teardown: C, test: T) -> ()
+    fn run_test_with_setup<S, C, T>(setup: S, teardown: C, test: T)
     where
-        S: FnOnce() -> (),
-        C: FnOnce() -> (),
-        T: FnOnce() -> () + panic::UnwindSafe,
+        S: FnOnce(),
+        C: FnOnce(),
+        T: FnOnce() + panic::UnwindSafe,
     {
         setup();
-        let result = panic::catch_unwind(|| test());
+        let result = panic::catch_unwind(test);
         teardown();
 
         assert!(result.is_ok());
@@ -248,7 +1510,7 @@ mod tests {
 
     struct RenameTestSetup {
         dir: String,
-        file_contents: Vec<(String, String)>,
+        file_contents: Vec<(PathBuf, String)>,
     }
 
     impl RenameTestSetup {
@@ -269,7 +1531,7 @@ mod tests {
             self
         }
 
-        fn full_path(&self, file: &str) -> PathBuf {
+        fn full_path<P: AsRef<Path>>(&self, file: P) -> PathBuf {
             let mut temp_path: PathBuf = PathBuf::new();
             temp_path.push(&self.dir);
             temp_path.push(file);
@@ -278,19 +1540,45 @@ mod tests {
 
         fn add_file(mut self, file: &str, contents: &str) -> Self {
             let file_path = self.full_path(file);
-            match fs::write(&file_path, contents) {
-                Ok(_) => self.file_contents.push((
-                    file_path.to_str().unwrap().to_string(),
-                    contents.to_string(),
-                )),
-                Err(_) => (),
+            if fs::write(&file_path, contents).is_ok() {
+                self.file_contents.push((file_path, contents.to_string()));
             }
 
             self
         }
     }
 
-    fn read_all(file: &str) -> String {
+    // Runs lines that rename their file, or delete it when the name is empty
+    fn bulk_rename(
+        froms: &[PathBuf],
+        tos: &[PathBuf],
+        options: &RenameOptions,
+    ) -> Result<i32, String> {
+        let tos: Vec<(Operation, PathBuf)> = tos
+            .iter()
+            .map(|to| {
+                if to.as_os_str().is_empty() {
+                    (Operation::Delete, to.clone())
+                } else {
+                    (Operation::Rename, to.clone())
+                }
+            })
+            .collect();
+        run(froms, &tos, options)
+    }
+
+    // Runs lines with any operation, counting the files changed
+    fn run(
+        froms: &[PathBuf],
+        tos: &[(Operation, PathBuf)],
+        options: &RenameOptions,
+    ) -> Result<i32, String> {
+        super::bulk_rename(froms, tos, options)
+            .map(|report| report.files() as i32)
+            .map_err(|e| e.to_string())
+    }
+
+    fn read_all(file: &Path) -> String {
         let mut contents = String::new();
 
         let mut f = File::open(file).expect("file not found");
@@ -301,6 +1589,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::useless_vec, clippy::needless_borrow)]
     fn rename_swap_test() {
         let setup = RenameTestSetup::with_temp_dir("rename_test")
             .init()
@@ -312,10 +1601,10 @@ mod tests {
         let (ref file_a, ref contents_a) = setup.file_contents[0];
         let (ref file_b, ref contents_b) = setup.file_contents[1];
 
-        let res = super::bulk_rename(
+        let res = bulk_rename(
             &vec![file_a.to_owned(), file_b.to_owned()],
             &vec![file_b.to_owned(), file_a.to_owned()],
-            false,
+            &RenameOptions::default(),
         );
 
         assert!(res.is_ok());
@@ -325,10 +1614,891 @@ mod tests {
     }
 
     #[test]
+    fn exchange_test() {
+        let setup = RenameTestSetup::with_temp_dir("exchange_test")
+            .init()
+            .add_file("A.txt", "a")
+            .add_file("B.txt", "b")
+            .add_file("C.txt", "c");
+
+        let path = |p: &str| setup.full_path(p);
+        let journal_dir = setup.full_path("journal");
+
+        // A swap is a single step
+        let res = bulk_rename(
+            &[path("A.txt"), path("B.txt")],
+            &[path("B.txt"), path("A.txt")],
+            &RenameOptions {
+                journal: Some(journal_dir.clone()),
+                ..RenameOptions::default()
+            },
+        );
+        assert_eq!(res, Ok(2));
+        assert_eq!(read_all(&path("A.txt")), "b");
+        assert_eq!(read_all(&path("B.txt")), "a");
+        let batch = journal::load(&journal_dir, None).unwrap();
+        assert_eq!(
+            batch.steps,
+            vec![Step::Exchange(Rename::new(path("A.txt"), path("B.txt")))]
+        );
+
+        assert_eq!(super::undo(&RealFs::new(), &journal_dir, None).unwrap().files(), 2);
+        assert_eq!(read_all(&path("A.txt")), "a");
+
+        // Renames leave files that appear at their target alone, unless told to replace them
+        let r = Rename::new(path("A.txt"), path("C.txt"));
+        assert!(Step::Rename(r.clone()).run(&RealFs::new()).is_err());
+        assert_eq!(read_all(&path("C.txt")), "c");
+        assert!(Step::Replace(r).run(&RealFs::new()).is_ok());
+        assert_eq!(read_all(&path("C.txt")), "a");
+    }
+
+    #[test]
+    fn report_test() {
+        let setup = RenameTestSetup::with_temp_dir("rename_report_test")
+            .init()
+            .add_file("A.txt", "a")
+            .add_file("B.txt", "b")
+            .add_file("C.txt", "c");
+
+        let path = |p: &str| setup.full_path(p);
+        let froms = [path("A.txt"), path("B.txt"), path("C.txt")];
+        let tos = [
+            (Operation::Rename, path("X.txt")),
+            (Operation::Rename, path("X.txt")),
+            (Operation::Rename, path("Y.txt")),
+        ];
+
+        // Conflicts give the lines involved, as numbered by the caller
+        match super::plan(&RealFs::new(), &froms, &tos, &[3, 1, 2], &RenameOptions::default()) {
+            Err(Error::Conflict { lines, .. }) => assert_eq!(lines, vec![1, 3]),
+            res => panic!("{:?}", res.map(|planned| planned.steps)),
+        }
+
+        // Skipped lines and failed steps are reported, and the rest still runs
+        let options = RenameOptions {
+            on_conflict: ConflictPolicy::Skip,
+            ..RenameOptions::default()
+        };
+        let planned = super::plan(&RealFs::new(), &froms, &tos, &[1, 2, 3], &options).unwrap();
+        fs::remove_file(path("C.txt")).unwrap();
+        let report = super::run(&RealFs::new(), &planned, &options).unwrap();
+
+        assert_eq!(report.files(), 1);
+        assert_eq!(
+            report.renamed,
+            vec![Step::Rename(Rename::new(path("A.txt"), path("X.txt")))]
+        );
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].0, 2);
+        assert_eq!(report.failed.len(), 1);
+        match report.failed[0].1 {
+            Error::Io { ref path, .. } => assert_eq!(*path, setup.full_path("C.txt")),
+            ref e => panic!("{}", e),
+        }
+        assert_eq!(read_all(&path("X.txt")), "a");
+        assert_eq!(read_all(&path("B.txt")), "b");
+    }
+
+    #[test]
+    fn rename_rotation_test() {
+        let setup = RenameTestSetup::with_temp_dir("rename_rotation_test")
+            .init()
+            .add_file("A.txt", "a")
+            .add_file("B.txt", "b")
+            .add_file("C.txt", "c");
+
+        let files: Vec<PathBuf> = setup.file_contents.iter().map(|f| f.0.clone()).collect();
+
+        let res = bulk_rename(
+            &files,
+            &[files[1].clone(), files[2].clone(), files[0].clone()],
+            &RenameOptions::default(),
+        );
+
+        assert_eq!(res, Ok(3));
+
+        assert_eq!(read_all(&files[0]), "c");
+        assert_eq!(read_all(&files[1]), "a");
+        assert_eq!(read_all(&files[2]), "b");
+        assert_eq!(fs::read_dir(&setup.dir).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn rename_dir_with_contents_test() {
+        let setup = RenameTestSetup::with_temp_dir("rename_dir_with_contents_test").init();
+        fs::create_dir_all(setup.full_path("d/sub")).unwrap();
+        let setup = setup.add_file("d/sub/x.txt", "x").add_file("d/y.txt", "y");
+
+        let path = |p: &str| setup.full_path(p);
+
+        // Parents are listed before their children, as a recursive listing does
+        let res = bulk_rename(
+            &[path("d"), path("d/sub"), path("d/sub/x.txt"), path("d/y.txt")],
+            &[path("e"), path("e/s"), path("e/s/z.txt"), path("e/y.txt")],
+            &RenameOptions::default(),
+        );
+
+        assert_eq!(res, Ok(3));
+
+        assert_eq!(read_all(&path("e/s/z.txt")), "x");
+        assert_eq!(read_all(&path("e/y.txt")), "y");
+        assert!(!Path::new(&path("d")).exists());
+    }
+
+    #[test]
+    fn transactional_rollback_test() {
+        let setup = RenameTestSetup::with_temp_dir("transactional_rollback_test").init();
+        fs::create_dir(setup.full_path("d")).unwrap();
+        let setup = setup
+            .add_file("d/A.txt", "a")
+            .add_file("d/B.txt", "b")
+            .add_file("d/C.txt", "c")
+            .add_file("D.txt", "d");
+
+        let path = |p: &str| setup.full_path(p);
+
+        // The rotation in the subdirectory runs first, then the missing file fails
+        let res = bulk_rename(
+            &[path("d/A.txt"), path("d/B.txt"), path("d/C.txt"), path("D.txt"), path("X.txt")],
+            &[path("d/B.txt"), path("d/C.txt"), path("d/A.txt"), path("E.txt"), path("Y.txt")],
+            &RenameOptions {
+                transactional: true,
+                ..RenameOptions::default()
+            },
+        );
+
+        let msg = res.unwrap_err();
+        assert!(msg.contains("Rolled back 5 renames"), "{}", msg);
+        assert!(!msg.contains("could not undo"), "{}", msg);
+
+        assert_eq!(read_all(&path("d/A.txt")), "a");
+        assert_eq!(read_all(&path("d/B.txt")), "b");
+        assert_eq!(read_all(&path("d/C.txt")), "c");
+        assert_eq!(read_all(&path("D.txt")), "d");
+        assert_eq!(fs::read_dir(setup.full_path("d")).unwrap().count(), 3);
+
+        // A file replaced without a trash is gone, so its rename is reverted but not undone
+        fs::write(path("F.txt"), "f").unwrap();
+        let res = bulk_rename(
+            &[path("D.txt"), path("X.txt")],
+            &[path("F.txt"), path("Y.txt")],
+            &RenameOptions {
+                transactional: true,
+                on_conflict: ConflictPolicy::Overwrite,
+                ..RenameOptions::default()
+            },
+        );
+
+        let msg = res.unwrap_err();
+        assert!(msg.contains("Rolled back 0 renames"), "{}", msg);
+        assert!(msg.contains("could not undo"), "{}", msg);
+        assert_eq!(read_all(&path("D.txt")), "d");
+        assert!(!Path::new(&path("F.txt")).exists());
+    }
+
+    #[test]
+    fn journal_undo_test() {
+        let setup = RenameTestSetup::with_temp_dir("journal_undo_test")
+            .init()
+            .add_file("A.txt", "a")
+            .add_file("B.txt", "b")
+            .add_file("C.txt", "c");
+
+        let path = |p: &str| setup.full_path(p);
+        let journal_dir = setup.full_path("journal");
+        let options = RenameOptions {
+            journal: Some(journal_dir.clone()),
+            ..RenameOptions::default()
+        };
+
+        let res = bulk_rename(
+            &[path("A.txt"), path("B.txt"), path("C.txt")],
+            &[path("B.txt"), path("A.txt"), path("D.txt")],
+            &options,
+        );
+        assert_eq!(res, Ok(3));
+        assert_eq!(read_all(&path("A.txt")), "b");
+
+        // Undoing refuses to clobber a file that appeared since
+        fs::write(path("C.txt"), "new").unwrap();
+        assert!(super::undo(&RealFs::new(), &journal_dir, None).is_err());
+        fs::remove_file(path("C.txt")).unwrap();
+
+        assert_eq!(super::undo(&RealFs::new(), &journal_dir, None).unwrap().files(), 3);
+        assert_eq!(read_all(&path("A.txt")), "a");
+        assert_eq!(read_all(&path("B.txt")), "b");
+        assert_eq!(read_all(&path("C.txt")), "c");
+        assert!(!Path::new(&path("D.txt")).exists());
+
+        // The batch is marked so it is not undone twice
+        assert!(super::undo(&RealFs::new(), &journal_dir, None).is_err());
+    }
+
+    #[test]
+    fn recover_interrupted_test() {
+        let setup = RenameTestSetup::with_temp_dir("recover_interrupted_test")
+            .init()
+            .add_file("A.txt", "a")
+            .add_file("B.txt", "b")
+            .add_file("C.txt", "c");
+
+        let path = |p: &str| setup.full_path(p);
+        let journal_dir = setup.full_path("journal");
+
+        let moves = super::with_temporary_moves(&[vec![
+            Rename::new(path("A.txt"), path("B.txt")),
+            Rename::new(path("B.txt"), path("C.txt")),
+            Rename::new(path("C.txt"), path("A.txt")),
+        ]]);
+        let plan: Vec<Step> = moves.iter().cloned().map(Step::Rename).collect();
+
+        let interrupt = |steps: usize, marked: usize| {
+            let mut log = journal::begin(&RealFs::new(), &journal_dir, &plan).unwrap();
+            for (i, step) in plan[..steps].iter().enumerate() {
+                step.run(&RealFs::new()).unwrap();
+                if i < marked {
+                    log.step_done(step).unwrap();
+                }
+            }
+        };
+
+        // Killed after two steps, with only the first one marked
+        interrupt(2, 1);
+        assert!(moves[0].to.exists());
+
+        assert_eq!(super::recover(&RealFs::new(), &journal_dir, false).unwrap()[0].steps.len(), 2);
+        assert_eq!(read_all(&path("A.txt")), "a");
+        assert_eq!(read_all(&path("B.txt")), "b");
+        assert_eq!(read_all(&path("C.txt")), "c");
+        assert!(super::recover(&RealFs::new(), &journal_dir, false).is_err());
+
+        interrupt(3, 3);
+        assert_eq!(super::recover(&RealFs::new(), &journal_dir, true).unwrap()[0].steps.len(), 1);
+        assert_eq!(read_all(&path("A.txt")), "c");
+        assert_eq!(read_all(&path("B.txt")), "a");
+        assert_eq!(read_all(&path("C.txt")), "b");
+        assert_eq!(fs::read_dir(&setup.dir).unwrap().count(), 4);
+
+        // An exchange leaves both names in place, so the journal tells whether it ran
+        let plan = [Step::Exchange(Rename::new(path("A.txt"), path("B.txt")))];
+        let interrupt = |recorded: bool, ran: bool| {
+            let mut log = journal::begin(&RealFs::new(), &journal_dir, &plan).unwrap();
+            if recorded {
+                let source = RealFs::new().symlink_metadata(&path("A.txt")).unwrap();
+                log.exchange_started(&plan[0], &source).unwrap();
+            }
+            if ran {
+                plan[0].run(&RealFs::new()).unwrap();
+            }
+        };
+
+        interrupt(true, true);
+        assert_eq!(super::recover(&RealFs::new(), &journal_dir, true).unwrap()[0].steps.len(), 0);
+        assert_eq!(read_all(&path("A.txt")), "a");
+        assert_eq!(read_all(&path("B.txt")), "c");
+
+        interrupt(true, false);
+        assert_eq!(super::recover(&RealFs::new(), &journal_dir, true).unwrap()[0].steps.len(), 1);
+        assert_eq!(read_all(&path("A.txt")), "c");
+        assert_eq!(read_all(&path("B.txt")), "a");
+
+        // Without a record of it there is no telling
+        interrupt(false, true);
+        assert!(super::recover(&RealFs::new(), &journal_dir, true).is_err());
+        assert_eq!(read_all(&path("A.txt")), "a");
+    }
+
+    #[test]
+    fn refuse_overwrite_test() {
+        let setup = RenameTestSetup::with_temp_dir("refuse_overwrite_test")
+            .init()
+            .add_file("A.txt", "a")
+            .add_file("B.txt", "b")
+            .add_file("C.txt", "c");
+
+        let path = |p: &str| setup.full_path(p);
+
+        // B.txt is renamed away in the same batch, C.txt is not
+        let froms = [path("A.txt"), path("B.txt")];
+        let tos = [path("B.txt"), path("C.txt")];
+
+        let msg = bulk_rename(&froms, &tos, &RenameOptions::default()).unwrap_err();
+        assert!(msg.contains(&format!("{}", path("C.txt").display())), "{}", msg);
+        assert!(!msg.contains(&format!("-> {}", path("B.txt").display())), "{}", msg);
+        assert_eq!(read_all(&path("A.txt")), "a");
+        assert_eq!(read_all(&path("C.txt")), "c");
+
+        let res = bulk_rename(
+            &froms,
+            &tos,
+            &RenameOptions {
+                on_conflict: ConflictPolicy::Overwrite,
+                ..RenameOptions::default()
+            },
+        );
+        assert_eq!(res, Ok(2));
+        assert_eq!(read_all(&path("B.txt")), "a");
+        assert_eq!(read_all(&path("C.txt")), "b");
+    }
+
+    #[test]
+    fn target_collision_test() {
+        let setup = RenameTestSetup::with_temp_dir("target_collision_test")
+            .init()
+            .add_file("A.txt", "a")
+            .add_file("B.txt", "b")
+            .add_file("C.txt", "c")
+            .add_file("D.txt", "d");
+
+        let path = |p: &str| setup.full_path(p);
+
+        let res = bulk_rename(
+            &[path("A.txt"), path("B.txt"), path("C.txt"), path("D.txt")],
+            &[path("X.txt"), path("D.txt"), path("X.txt"), path("D.txt")],
+            &RenameOptions::default(),
+        );
+
+        let msg = res.unwrap_err();
+        assert!(msg.contains(&format!("{}: lines 1, 3", path("X.txt").display())), "{}", msg);
+        assert!(msg.contains(&format!("{}: lines 2, 4", path("D.txt").display())), "{}", msg);
+
+        // Nothing has been renamed
+        assert_eq!(read_all(&path("A.txt")), "a");
+        assert_eq!(read_all(&path("B.txt")), "b");
+        assert!(!Path::new(&path("X.txt")).exists());
+    }
+
+    #[test]
+    fn conflict_policy_test() {
+        let setup = RenameTestSetup::with_temp_dir("conflict_policy_test").init();
+        let path = |p: &str| setup.full_path(p);
+
+        let run = |policy: ConflictPolicy| {
+            for (file, contents) in &[("A.txt", "a"), ("B.txt", "b"), ("C.txt", "c")] {
+                fs::write(path(file), contents).unwrap();
+            }
+            let tos = [
+                (Operation::Rename, path("C.txt")),
+                (Operation::Rename, path("C.txt")),
+            ];
+            let options = RenameOptions {
+                on_conflict: policy,
+                ..RenameOptions::default()
+            };
+            let report = super::bulk_rename(&[path("A.txt"), path("B.txt")], &tos, &options);
+            let report = report.unwrap();
+            let skipped: Vec<usize> = report.skipped.iter().map(|s| s.0).collect();
+            (report.files(), skipped)
+        };
+
+        // Everything but the existing file is skipped
+        assert_eq!(run(ConflictPolicy::Skip), (0, vec![1, 2]));
+        assert_eq!(read_all(&path("C.txt")), "c");
+
+        assert_eq!(run(ConflictPolicy::Suffix), (2, vec![]));
+        assert_eq!(read_all(&path("C.txt")), "c");
+        assert_eq!(read_all(&path("C (1).txt")), "a");
+        assert_eq!(read_all(&path("C (2).txt")), "b");
+
+        // The existing file is kept aside, the last line wins and the others stay
+        assert_eq!(run(ConflictPolicy::Backup), (2, vec![1]));
+        assert_eq!(read_all(&path("C.txt~")), "c");
+        assert_eq!(read_all(&path("C.txt")), "b");
+        assert_eq!(read_all(&path("A.txt")), "a");
+
+        assert_eq!(run(ConflictPolicy::Overwrite), (1, vec![1]));
+        assert_eq!(read_all(&path("C.txt")), "b");
+        assert_eq!(read_all(&path("A.txt")), "a");
+
+        // Prompting aborts unless the options give a prompt to ask
+        let (froms, tos) = ([path("A.txt")], [(Operation::Rename, path("C.txt"))]);
+        let options = RenameOptions {
+            on_conflict: ConflictPolicy::Prompt,
+            ..RenameOptions::default()
+        };
+        assert!(super::bulk_rename(&froms, &tos, &options).is_err());
+
+        let options = RenameOptions {
+            prompt: Some(Arc::new(|conflict: &Conflict| {
+                assert!(conflict.existing && conflict.claims.len() == 1);
+                ConflictPolicy::Skip
+            })),
+            ..options
+        };
+        let report = super::bulk_rename(&froms, &tos, &options).unwrap();
+        assert_eq!((report.files(), report.skipped.len()), (0, 1));
+    }
+
+    #[test]
+    fn move_between_depths_test() {
+        let setup = RenameTestSetup::with_temp_dir("move_between_depths_test").init();
+        fs::create_dir_all(setup.full_path("a")).unwrap();
+        fs::create_dir_all(setup.full_path("b/c")).unwrap();
+        let setup = setup
+            .add_file("a/x.txt", "x")
+            .add_file("a/keep.txt", "k")
+            .add_file("b/c/y.txt", "y");
+
+        let path = |p: &str| setup.full_path(p);
+
+        // `a` is renamed in place while one of its files moves deeper into the renamed directory
+        let res = bulk_rename(
+            &[path("a"), path("a/x.txt"), path("b/c/y.txt")],
+            &[path("d"), path("d/new/sub/x.txt"), path("y.txt")],
+            &RenameOptions {
+                prune_empty: true,
+                ..RenameOptions::default()
+            },
+        );
+
+        assert_eq!(res, Ok(3));
+        assert_eq!(read_all(&path("d/new/sub/x.txt")), "x");
+        assert_eq!(read_all(&path("d/keep.txt")), "k");
+        assert_eq!(read_all(&path("y.txt")), "y");
+
+        // Only `b/c` had a file moved out of it, so `b` is left although it is empty now
+        assert!(!Path::new(&path("b/c")).exists());
+        assert!(Path::new(&path("b")).exists());
+
+        // A directory that every file is moved out of goes along with one inside it
+        fs::create_dir_all(path("e/f")).unwrap();
+        fs::write(path("e/z.txt"), "z").unwrap();
+        fs::write(path("e/f/w.txt"), "w").unwrap();
+        let res = bulk_rename(
+            &[path("e/z.txt"), path("e/f/w.txt")],
+            &[path("z.txt"), path("w.txt")],
+            &RenameOptions {
+                prune_empty: true,
+                ..RenameOptions::default()
+            },
+        );
+        assert_eq!(res, Ok(2));
+        assert!(!Path::new(&path("e")).exists());
+        assert!(Path::new(&setup.dir).exists());
+    }
+
+    #[test]
+    fn move_frees_in_place_target_test() {
+        let setup = RenameTestSetup::with_temp_dir("move_frees_in_place_target_test").init();
+        fs::create_dir_all(setup.full_path("a")).unwrap();
+        let setup = setup.add_file("a/x", "x").add_file("a/y", "y");
+
+        let path = |p: &str| setup.full_path(p);
+
+        // `a/x` moves out of `a` before `a/y` is renamed to it
+        let res = bulk_rename(
+            &[path("a/x"), path("a/y")],
+            &[path("b/x"), path("a/x")],
+            &RenameOptions::default(),
+        );
+
+        assert_eq!(res, Ok(2));
+        assert_eq!(read_all(&path("b/x")), "x");
+        assert_eq!(read_all(&path("a/x")), "y");
+        assert_eq!(fs::read_dir(path("a")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn move_or_rename_parent_test() {
+        let setup = RenameTestSetup::with_temp_dir("move_or_rename_parent_test").init();
+        fs::create_dir_all(setup.full_path("photos/2019")).unwrap();
+        let setup = setup
+            .add_file("photos/2019/a.jpg", "a")
+            .add_file("photos/2019/b.jpg", "b");
+
+        let path = |p: &str| setup.full_path(p);
+
+        // Only the edited file moves, into a directory that is created for it
+        let res = bulk_rename(
+            &[path("photos/2019/a.jpg")],
+            &[path("photos/2020/a.jpg")],
+            &RenameOptions::default(),
+        );
+
+        assert_eq!(res, Ok(1));
+        assert_eq!(read_all(&path("photos/2020/a.jpg")), "a");
+        assert_eq!(read_all(&path("photos/2019/b.jpg")), "b");
+
+        // Asking for directory renames takes the rest of the directory along
+        let res = bulk_rename(
+            &[path("photos/2019/b.jpg")],
+            &[path("photos/2018/b.jpg")],
+            &RenameOptions {
+                rename_dirs: true,
+                ..RenameOptions::default()
+            },
+        );
+
+        assert_eq!(res, Ok(1));
+        assert_eq!(read_all(&path("photos/2018/b.jpg")), "b");
+        assert!(!Path::new(&path("photos/2019")).exists());
+    }
+
+    #[test]
+    fn merge_dir_renames_test() {
+        let setup = RenameTestSetup::with_temp_dir("merge_dir_renames_test").init();
+        fs::create_dir_all(setup.full_path("d")).unwrap();
+        let setup = setup
+            .add_file("d/x.txt", "x")
+            .add_file("d/y.txt", "y")
+            .add_file("d/z.txt", "z");
+
+        let path = |p: &str| setup.full_path(p);
+        let options = RenameOptions {
+            rename_dirs: true,
+            ..RenameOptions::default()
+        };
+
+        // Two lines take `d` to different names
+        let res = bulk_rename(
+            &[path("d/x.txt"), path("d/y.txt")],
+            &[path("e/x.txt"), path("f/y.txt")],
+            &options,
+        );
+
+        let msg = res.unwrap_err();
+        assert!(msg.contains("Contradictory renames"), "{}", msg);
+        assert!(msg.contains("line 1") && msg.contains("line 2"), "{}", msg);
+        assert!(Path::new(&path("d")).exists());
+
+        // Every line agrees, so `d` is renamed once
+        let res = bulk_rename(
+            &[path("d/x.txt"), path("d/y.txt"), path("d/z.txt")],
+            &[path("e/x.txt"), path("e/y.txt"), path("e/z.txt")],
+            &options,
+        );
+
+        assert_eq!(res, Ok(1));
+        assert_eq!(read_all(&path("e/x.txt")), "x");
+        assert_eq!(read_all(&path("e/z.txt")), "z");
+    }
+
+    #[test]
+    fn delete_test() {
+        let setup = RenameTestSetup::with_temp_dir("delete_test").init();
+        fs::create_dir_all(setup.full_path("full/sub")).unwrap();
+        fs::create_dir_all(setup.full_path("empty")).unwrap();
+        let setup = setup
+            .add_file("A.txt", "a")
+            .add_file("B.txt", "b")
+            .add_file("full/sub/x.txt", "x");
+
+        let path = |p: &str| setup.full_path(p);
+        let journal_dir = setup.full_path("journal");
+
+        // B.txt is deleted to make room for A.txt
+        let res = bulk_rename(
+            &[path("A.txt"), path("B.txt"), path("empty")],
+            &[path("B.txt"), PathBuf::new(), PathBuf::new()],
+            &RenameOptions {
+                journal: Some(journal_dir.clone()),
+                ..RenameOptions::default()
+            },
+        );
+        assert_eq!(res, Ok(3));
+        assert_eq!(read_all(&path("B.txt")), "a");
+        assert!(!path("empty").exists());
+
+        // Deletions are journaled along with the renames
+        let batch = journal::load(&journal_dir, None).unwrap();
+        assert_eq!(batch.steps.len(), 3);
+        assert_eq!(batch.steps[0].path(), path("B.txt").as_path());
+
+        // Directories that are not empty need a recursive deletion
+        let res = bulk_rename(
+            &[path("full")],
+            &[PathBuf::new()],
+            &RenameOptions::default(),
+        );
+        assert!(res.is_ok());
+        assert!(path("full/sub/x.txt").exists());
+
+        let res = bulk_rename(
+            &[path("full")],
+            &[PathBuf::new()],
+            &RenameOptions {
+                recursive_delete: true,
+                ..RenameOptions::default()
+            },
+        );
+        assert_eq!(res, Ok(1));
+        assert!(!path("full").exists());
+
+        // A file cannot be renamed out of a directory that is deleted
+        fs::create_dir_all(path("d")).unwrap();
+        fs::write(path("d/y.txt"), "y").unwrap();
+        let res = bulk_rename(
+            &[path("d"), path("d/y.txt")],
+            &[PathBuf::new(), path("y.txt")],
+            &RenameOptions::default(),
+        );
+        assert!(res.unwrap_err().contains("deleted with line 1"));
+    }
+
+    #[test]
+    fn trash_test() {
+        let setup = RenameTestSetup::with_temp_dir("rename_trash_test")
+            .init()
+            .add_file("A.txt", "a")
+            .add_file("B.txt", "b")
+            .add_file("C.txt", "c");
+
+        let path = |p: &str| setup.full_path(p);
+        let trash = setup.full_path("Trash");
+        let journal_dir = setup.full_path("journal");
+        let options = RenameOptions {
+            trash: Some(trash.clone()),
+            on_conflict: ConflictPolicy::Overwrite,
+            journal: Some(journal_dir.clone()),
+            ..RenameOptions::default()
+        };
+
+        // C.txt is deleted and B.txt is replaced, both end up in the trash
+        let res = bulk_rename(
+            &[path("A.txt"), path("./C.txt")],
+            &[path("B.txt"), PathBuf::new()],
+            &options,
+        );
+        assert_eq!(res, Ok(2));
+        assert_eq!(read_all(&path("B.txt")), "a");
+        assert!(!path("C.txt").exists());
+        assert_eq!(read_all(&trash.join("files/B.txt")), "b");
+        assert_eq!(read_all(&trash.join("files/C.txt")), "c");
+        // The info file gives where it came from without the `.`
+        let info = read_all(&trash.join("info/C.txt.trashinfo"));
+        assert!(info.contains(&format!("\nPath={}\n", path("C.txt").display())));
+
+        // Unlike deleted files, trashed ones are brought back by an undo
+        assert!(super::undo(&RealFs::new(), &journal_dir, None).is_ok());
+        assert_eq!(read_all(&path("A.txt")), "a");
+        assert_eq!(read_all(&path("B.txt")), "b");
+        assert_eq!(read_all(&path("C.txt")), "c");
+        assert_eq!(fs::read_dir(trash.join("files")).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(trash.join("info")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn mixed_operations_test() {
+        let setup = RenameTestSetup::with_temp_dir("mixed_operations_test")
+            .init()
+            .add_file("A.txt", "a")
+            .add_file("B.txt", "b")
+            .add_file("C.txt", "c")
+            .add_file("D.txt", "d")
+            .add_file("E.txt", "e");
+
+        let path = |p: &str| setup.full_path(p);
+        let journal_dir = setup.full_path("journal");
+        let options = RenameOptions {
+            journal: Some(journal_dir.clone()),
+            ..RenameOptions::default()
+        };
+
+        // The copy of A.txt takes the name B.txt is renamed away from
+        let res = run(
+            &[path("A.txt"), path("B.txt"), path("C.txt"), path("D.txt"), path("E.txt")],
+            &[
+                (Operation::Copy, path("B.txt")),
+                (Operation::Rename, path("F.txt")),
+                (Operation::Symlink, path("links/C.txt")),
+                (Operation::Hardlink, path("G.txt")),
+                (Operation::Delete, PathBuf::new()),
+            ],
+            &options,
+        );
+        assert_eq!(res, Ok(5));
+        assert_eq!(read_all(&path("A.txt")), "a");
+        assert_eq!(read_all(&path("B.txt")), "a");
+        assert_eq!(read_all(&path("F.txt")), "b");
+        assert_eq!(fs::read_link(path("links/C.txt")).unwrap(), path("C.txt"));
+        assert_eq!(read_all(&path("G.txt")), "d");
+        assert!(!path("E.txt").exists());
+
+        // Undoing leaves a file that has taken the place of a copy or link alone
+        fs::write(path("B.txt"), "changed").unwrap();
+        assert!(super::undo(&RealFs::new(), &journal_dir, None).is_err());
+        assert_eq!(read_all(&path("B.txt")), "changed");
+        fs::write(path("B.txt"), "a").unwrap();
+        fs::remove_file(path("G.txt")).unwrap();
+        fs::write(path("G.txt"), "d").unwrap();
+        assert!(super::undo(&RealFs::new(), &journal_dir, None).is_err());
+        assert_eq!(read_all(&path("G.txt")), "d");
+        fs::remove_file(path("G.txt")).unwrap();
+        fs::hard_link(path("D.txt"), path("G.txt")).unwrap();
+        fs::remove_file(path("links/C.txt")).unwrap();
+        copy::symlink(&path("D.txt"), &path("links/C.txt")).unwrap();
+        assert!(super::undo(&RealFs::new(), &journal_dir, None).is_err());
+        fs::remove_file(path("links/C.txt")).unwrap();
+        copy::symlink(&path("C.txt"), &path("links/C.txt")).unwrap();
+
+        // Copies and links are removed again by an undo
+        assert!(super::undo(&RealFs::new(), &journal_dir, None).is_ok());
+        assert_eq!(read_all(&path("B.txt")), "b");
+        assert!(!path("F.txt").exists());
+        assert!(!path("links/C.txt").exists());
+        assert!(!path("G.txt").exists());
+
+        // Contents of a copied directory are copied along with it
+        fs::create_dir(path("dir")).unwrap();
+        fs::write(path("dir/x.txt"), "x").unwrap();
+        let res = run(
+            &[path("dir"), path("dir/x.txt")],
+            &[(Operation::Copy, path("copy")), (Operation::Copy, path("copy/x.txt"))],
+            &RenameOptions::default(),
+        );
+        assert_eq!(res, Ok(1));
+        assert_eq!(read_all(&path("copy/x.txt")), "x");
+
+        // Keeping a file keeps it in the way of other lines
+        let res = run(
+            &[path("A.txt"), path("B.txt")],
+            &[(Operation::Keep, path("A.txt")), (Operation::Copy, path("A.txt"))],
+            &RenameOptions::default(),
+        );
+        assert!(res.is_err());
+        let res = run(
+            &[path("A.txt")],
+            &[(Operation::Copy, path("B.txt"))],
+            &RenameOptions::default(),
+        );
+        assert!(res.unwrap_err().contains("existing file, line 1"));
+    }
+
+    #[test]
+    fn links_test() {
+        let setup = RenameTestSetup::with_temp_dir("links_test")
+            .init()
+            .add_file("A.txt", "a")
+            .add_file("B.txt", "b");
+
+        let path = |p: &str| setup.full_path(p);
+        let froms = [path("A.txt"), path("B.txt")];
+        let tos = [
+            (Operation::Symlink, path("view/one/A.txt")),
+            (Operation::Hardlink, path("view/B.txt")),
+        ];
+        let options = RenameOptions {
+            relative_links: true,
+            ..RenameOptions::default()
+        };
+
+        // Missing directories are made, and symlinks can point up to their file
+        assert_eq!(run(&froms, &tos, &options), Ok(2));
+        assert_eq!(fs::read_link(path("view/one/A.txt")).unwrap(), PathBuf::from("../../A.txt"));
+        assert_eq!(read_all(&path("view/one/A.txt")), "a");
+        fs::write(path("B.txt"), "changed").unwrap();
+        assert_eq!(read_all(&path("view/B.txt")), "changed");
+
+        // Running again leaves links that are up to date alone
+        assert_eq!(run(&froms, &tos, &options), Ok(0));
+
+        // And updates symlinks that are not
+        assert_eq!(run(&froms, &tos, &RenameOptions::default()), Ok(1));
+        assert_eq!(fs::read_link(path("view/one/A.txt")).unwrap(), path("A.txt"));
+
+
+        // But a file in the way is no link of theirs, even a former hard link to a file that has
+        // been written anew since, and is left to the conflict policy
+        fs::remove_file(path("B.txt")).unwrap();
+        fs::write(path("B.txt"), "new").unwrap();
+        let options = RenameOptions::default();
+        match super::plan(&RealFs::new(), &froms[1..], &tos[1..], &[1], &options) {
+            Err(Error::Conflict { lines, .. }) => assert_eq!(lines, vec![1]),
+            res => panic!("{:?}", res.map(|planned| planned.steps)),
+        }
+        assert_eq!(read_all(&path("view/B.txt")), "changed");
+
+        fs::write(path("precious"), "p").unwrap();
+        let (froms, tos) = ([path("A.txt")], [(Operation::Hardlink, path("precious"))]);
+        match super::plan(&RealFs::new(), &froms, &tos, &[1], &options) {
+            Err(Error::Conflict { lines, .. }) => assert_eq!(lines, vec![1]),
+            res => panic!("{:?}", res.map(|planned| planned.steps)),
+        }
+        assert_eq!(read_all(&path("precious")), "p");
+    }
+
+    #[test]
+    fn non_utf8_names_test() {
+        let setup = RenameTestSetup::with_temp_dir("non_utf8_names_test").init();
+        let journal_dir = setup.full_path("journal");
+
+        // Latin-1 `café.txt`
+        let latin1 = setup.full_path(escape::from_bytes(b"caf\xe9.txt".to_vec()));
+        fs::write(&latin1, "c").unwrap();
+
+        let res = bulk_rename(
+            ::std::slice::from_ref(&latin1),
+            &[setup.full_path("café.txt")],
+            &RenameOptions {
+                journal: Some(journal_dir.clone()),
+                ..RenameOptions::default()
+            },
+        );
+        assert_eq!(res, Ok(1));
+        assert_eq!(read_all(&setup.full_path("café.txt")), "c");
+
+        // The journal gives back the exact original bytes
+        assert_eq!(super::undo(&RealFs::new(), &journal_dir, None).unwrap().files(), 1);
+        assert_eq!(read_all(&latin1), "c");
+    }
+
+    #[test]
+    fn plan_renames_test() {
+        // A chain has to run from its free end
+        {
+            let (ordered, cycles) = super::plan_renames(&[
+                Rename::new("/a", "/b"),
+                Rename::new("/b", "/c"),
+                Rename::new("/c", "/d"),
+            ]);
+
+            assert_eq!(
+                ordered,
+                vec![
+                    Rename::new("/c", "/d"),
+                    Rename::new("/b", "/c"),
+                    Rename::new("/a", "/b"),
+                ]
+            );
+            assert!(cycles.is_empty());
+        }
+
+        // Cycles are split out, independent renames are left alone
+        {
+            let (ordered, cycles) = super::plan_renames(&[
+                Rename::new("/a", "/b"),
+                Rename::new("/x", "/y"),
+                Rename::new("/b", "/c"),
+                Rename::new("/c", "/a"),
+                Rename::new("/p", "/q"),
+                Rename::new("/q", "/p"),
+            ]);
+
+            assert_eq!(ordered, vec![Rename::new("/x", "/y")]);
+            assert_eq!(
+                cycles,
+                vec![
+                    vec![
+                        Rename::new("/a", "/b"),
+                        Rename::new("/b", "/c"),
+                        Rename::new("/c", "/a"),
+                    ],
+                    vec![Rename::new("/p", "/q"), Rename::new("/q", "/p")],
+                ]
+            );
+
+            // One temporary move per cycle
+            assert_eq!(super::with_temporary_moves(&cycles).len(), 7);
+        }
+    }
+
+    #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn rename_sequence_test() {
         // Successful renames
         {
-            let result = Rename::rename_sequence("/x/y/z", "/a/b/c");
+            let result = Rename::rename_sequence(Path::new("/x/y/z"), Path::new("/a/b/c"));
 
             match result {
                 Ok(res) => {
@@ -347,7 +2517,7 @@ mod tests {
             }
         }
         {
-            let result = Rename::rename_sequence("/x", "/a");
+            let result = Rename::rename_sequence(Path::new("/x"), Path::new("/a"));
 
             match result {
                 Ok(res) => {
@@ -360,17 +2530,17 @@ mod tests {
 
         // Following ones should fail due to them not being compatible paths
         {
-            let result = Rename::rename_sequence("/A/B/C", "/X/Y");
+            let result = Rename::rename_sequence(Path::new("/A/B/C"), Path::new("/X/Y"));
             assert!(result.is_err());
         }
         {
-            let result = Rename::rename_sequence("/X/Y", "/A/B/C");
+            let result = Rename::rename_sequence(Path::new("/X/Y"), Path::new("/A/B/C"));
             assert!(result.is_err());
         }
 
         // This case should also return empty vector of renames since we don't need to rename anything here
         {
-            let result = Rename::rename_sequence("/x", "/x");
+            let result = Rename::rename_sequence(Path::new("/x"), Path::new("/x"));
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), vec![]);
         }
@@ -0,0 +1,259 @@
+use std::collections::HashSet;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use libc;
+
+use escape;
+use filesystem::Filesystem;
+use rename;
+
+const INFO_EXTENSION: &str = "trashinfo";
+
+/// Removed files go to the trash as described by the freedesktop.org Trash specification, so that
+/// desktop tools can restore them. The home trash is kept under $XDG_DATA_HOME/Trash, falling back
+/// to ~/.local/share/Trash.
+pub fn home_dir() -> Option<PathBuf> {
+    match env::var_os("XDG_DATA_HOME") {
+        Some(ref data) if Path::new(data).is_absolute() => Some(Path::new(data).join("Trash")),
+        _ => env::var_os("HOME").map(|home| Path::new(&home).join(".local/share/Trash")),
+    }
+}
+
+// Files can only be moved into a trash on their own filesystem. That is the home trash if it is
+// on the same one, or otherwise a trash at the top of the mount the file is on: `.Trash/$uid` if
+// the administrator has set up a sticky `.Trash`, and `.Trash-$uid` if not.
+pub fn dir_for(fs: &dyn Filesystem, path: &Path, home: &Path) -> io::Result<PathBuf> {
+    let dev = fs.symlink_metadata(path)?.dev;
+    if device_of(fs, home)? == dev {
+        return Ok(home.to_path_buf());
+    }
+
+    let absolute = fs.current_dir()?.join(path);
+    let mut top = absolute.parent().unwrap_or(&absolute).to_path_buf();
+    while let Some(parent) = top.parent().map(Path::to_path_buf) {
+        if fs.metadata(&parent)?.dev != dev {
+            break;
+        }
+        top = parent;
+    }
+
+    let uid = fs.user().uid;
+    let shared = top.join(".Trash");
+    // The administrator shares a trash at the top of a mount by making it sticky
+    if fs.symlink_metadata(&shared).is_ok_and(|md| md.is_dir() && md.mode & 0o1000 != 0) {
+        Ok(shared.join(uid.to_string()))
+    } else {
+        Ok(top.join(format!(".Trash-{}", uid)))
+    }
+}
+
+// The home trash may not exist yet, in which case its closest existing parent decides
+fn device_of(fs: &dyn Filesystem, path: &Path) -> io::Result<u64> {
+    let mut current = Some(path);
+    while let Some(p) = current {
+        if let Ok(md) = fs.metadata(p) {
+            return Ok(md.dev);
+        }
+        current = p.parent();
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    ))
+}
+
+// Picks the name a file is kept under in the trash, one that is neither used in the trash yet
+// nor by another file of the same batch
+pub fn name_in(
+    fs: &dyn Filesystem,
+    trash: &Path,
+    path: &Path,
+    taken: &mut HashSet<PathBuf>,
+) -> PathBuf {
+    let name = path.file_name().map(OsString::from).unwrap_or_default();
+
+    for n in 1.. {
+        let mut candidate = name.clone();
+        if n > 1 {
+            candidate.push(format!(".{}", n));
+        }
+
+        let trashed = trash.join("files").join(&candidate);
+        if !taken.contains(&trashed) && !fs.exists(&trashed) && !fs.exists(&info_path(&trashed))
+        {
+            taken.insert(trashed.clone());
+            return trashed;
+        }
+    }
+
+    unreachable!()
+}
+
+// Moves a file to the name picked for it in the trash. The info file is written first, as it
+// is what reserves the name.
+pub fn put(fs: &dyn Filesystem, path: &Path, trashed: &Path) -> io::Result<()> {
+    let info = info_path(trashed);
+    let trash = trash_of(trashed);
+    if fs::symlink_metadata(trash).is_err() {
+        fs::create_dir_all(trash)?;
+        make_private(trash)?;
+    }
+    fs::create_dir_all(trash.join("files"))?;
+    fs::create_dir_all(trash.join("info"))?;
+
+    let original = rename::absolute(fs, path)?;
+    let mut file = OpenOptions::new().write(true).create_new(true).open(&info)?;
+    file.write_all(info_for(&original).as_bytes())?;
+
+    if let Err(e) = fs::rename(path, trashed) {
+        let _ = fs::remove_file(&info);
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Takes a file back out of the trash
+pub fn restore(trashed: &Path, path: &Path) -> io::Result<()> {
+    fs::rename(trashed, path)?;
+    fs::remove_file(info_path(trashed))
+}
+
+// What the info file of a trashed file says about it
+pub fn info_for(original: &Path) -> String {
+    format!(
+        "[Trash Info]\nPath={}\nDeletionDate={}\n",
+        url_escape(original),
+        deletion_date()
+    )
+}
+
+// Only the owner of a trash may look into it
+#[cfg(unix)]
+fn make_private(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+}
+
+#[cfg(not(unix))]
+fn make_private(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn trash_of(trashed: &Path) -> &Path {
+    trashed.parent().and_then(Path::parent).unwrap_or(trashed)
+}
+
+// `<trash>/files/<name>` is described by `<trash>/info/<name>.trashinfo`
+pub fn info_path(trashed: &Path) -> PathBuf {
+    let trash = trash_of(trashed);
+    let mut name = trashed.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".");
+    name.push(INFO_EXTENSION);
+    trash.join("info").join(name)
+}
+
+// Paths are stored escaped as in URLs, byte by byte
+fn url_escape(path: &Path) -> String {
+    let mut escaped = String::new();
+    for &b in escape::as_bytes(path.as_os_str()) {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                escaped.push(b as char)
+            }
+            b => escaped.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    escaped
+}
+
+// The deletion date is in local time, as YYYY-MM-DDThh:mm:ss
+#[cfg(unix)]
+fn deletion_date() -> String {
+    unsafe {
+        let now = libc::time(::std::ptr::null_mut());
+        let mut tm: libc::tm = ::std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            tm.tm_hour,
+            tm.tm_min,
+            tm.tm_sec
+        )
+    }
+}
+
+// Without a portable way to find the local time zone, the date is given in UTC
+#[cfg(not(unix))]
+fn deletion_date() -> String {
+    use journal;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    let (days, time) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    let (year, month, day) = journal::civil_date(days);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use filesystem::RealFs;
+    use std::collections::HashSet;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn trash_test() {
+        let dir = env::temp_dir().join("trash_test");
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir(&dir).unwrap();
+
+        let trash = dir.join("Trash");
+        let file = dir.join("a b%.txt");
+        fs::write(&file, "a").unwrap();
+        fs::create_dir_all(trash.join("files")).unwrap();
+        fs::write(trash.join("files/a b%.txt"), "older").unwrap();
+
+        assert_eq!(super::dir_for(&RealFs::new(), &file, &trash).unwrap(), trash);
+
+        let mut taken = HashSet::new();
+        let trashed = super::name_in(&RealFs::new(), &trash, &file, &mut taken);
+        assert_eq!(trashed, trash.join("files/a b%.txt.2"));
+
+        super::put(&RealFs::new(), &file, &trashed).unwrap();
+        assert!(!file.exists());
+        assert_eq!(fs::read_to_string(&trashed).unwrap(), "a");
+
+        let info = fs::read_to_string(trash.join("info/a b%.txt.2.trashinfo")).unwrap();
+        let lines: Vec<&str> = info.lines().collect();
+        assert_eq!(lines[0], "[Trash Info]");
+        assert_eq!(lines[1], format!("Path={}/a%20b%25.txt", dir.display()));
+        assert!(lines[2].starts_with("DeletionDate=") && lines[2].len() == 32);
+
+        super::restore(&trashed, &file).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "a");
+        assert!(!Path::new(&trash.join("info/a b%.txt.2.trashinfo")).exists());
+    }
+}